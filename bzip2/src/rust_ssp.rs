@@ -90,7 +90,7 @@ pub fn rust_ssp(threads: usize, file_action: &str, file_name: &str,) {
 
 	    }
 
-    	let collection = pipeline.collect();
+    	let collection = pipeline.collect().unwrap();
         
         let system_duration = start.elapsed().expect("Failed to get render time?");
 		let in_sec = system_duration.as_secs() as f64 + system_duration.subsec_nanos() as f64 * 1e-9;
@@ -185,7 +185,7 @@ pub fn rust_ssp(threads: usize, file_action: &str, file_name: &str,) {
             }).unwrap();
         }
 
-    	let collection = pipeline.collect();
+    	let collection = pipeline.collect().unwrap();
 
 		let system_duration = start.elapsed().expect("Failed to get render time?");
 		let in_sec = system_duration.as_secs() as f64 + system_duration.subsec_nanos() as f64 * 1e-9;
@@ -261,7 +261,7 @@ pub fn rust_ssp_io(threads: usize, file_action: &str, file_name: &str,) {
 
 	    }
         
-	    pipeline.end_and_wait();
+	    pipeline.end_and_wait().unwrap();
 
         let system_duration = start.elapsed().expect("Failed to get render time?");
 		let in_sec = system_duration.as_secs() as f64 + system_duration.subsec_nanos() as f64 * 1e-9;
@@ -347,7 +347,7 @@ pub fn rust_ssp_io(threads: usize, file_action: &str, file_name: &str,) {
             }).unwrap();
         }
 
-        pipeline.end_and_wait();
+        pipeline.end_and_wait().unwrap();
 
 		let system_duration = start.elapsed().expect("Failed to get render time?");
 		let in_sec = system_duration.as_secs() as f64 + system_duration.subsec_nanos() as f64 * 1e-9;
@@ -15,16 +15,12 @@ mod common;
 struct MatData {
     frame: Mat,
 }
-unsafe impl Sync for MatData {}
-unsafe impl Send for MatData {}
 
 struct EyesData {
     frame: Mat,
     equalized: Mat,
     faces: types::VectorOfRect,
 }
-unsafe impl Sync for EyesData {}
-unsafe impl Send for EyesData {}
 
 struct DetectFaces{
     face_detector : objdetect::CascadeClassifier,
@@ -36,18 +32,18 @@ impl DetectFaces {
         DetectFaces { face_detector : face_detector } 
     }
 }
-impl InOut<MatData,EyesData> for DetectFaces {
-    fn process(&mut self, in_data: MatData) -> Option<EyesData>{
+impl TryInOut<MatData,EyesData,opencv::Error> for DetectFaces {
+    fn process(&mut self, in_data: MatData) -> opencv::Result<Option<EyesData>>{
         // Convert to gray and equalize frame
-        let equalized = common::prepare_frame(&in_data.frame).unwrap();
+        let equalized = common::prepare_frame(&in_data.frame)?;
 
         // Detect faces
-        let faces = common::detect_faces(&equalized,&mut self.face_detector).unwrap();
+        let faces = common::detect_faces(&equalized,&mut self.face_detector)?;
 
         let out_data = EyesData{frame : in_data.frame, 
                                 equalized : equalized, 
                                 faces : faces};
-        Some(out_data)
+        Ok(Some(out_data))
     }
 }
 
@@ -62,18 +58,18 @@ impl DetectEyes {
         DetectEyes { eye_detector : eye_detector } 
     }
 }
-impl InOut<EyesData,MatData> for DetectEyes {
-    fn process(&mut self, mut in_data : EyesData) -> Option<MatData>{
+impl TryInOut<EyesData,MatData,opencv::Error> for DetectEyes {
+    fn process(&mut self, mut in_data : EyesData) -> opencv::Result<Option<MatData>>{
         for face in in_data.faces {
 
-            let eyes =  common::detect_eyes(&core::Mat::roi(&in_data.equalized,face).unwrap(),
-                                            &mut self.eye_detector).unwrap();
+            let eyes =  common::detect_eyes(&core::Mat::roi(&in_data.equalized,face)?,
+                                            &mut self.eye_detector)?;
 
-            common::draw_in_frame(&mut in_data.frame,&eyes,&face).unwrap();
+            common::draw_in_frame(&mut in_data.frame,&eyes,&face)?;
 
         }
         let out_data = MatData{ frame : in_data.frame };
-        Some(out_data)
+        Ok(Some(out_data))
     }
 }
 
@@ -121,8 +117,8 @@ pub fn rust_spp_eye_tracker(input_video: &String, nthreads: i32) -> opencv::Resu
     let fps_out = video_in.get(videoio::VideoCaptureProperties::CAP_PROP_FPS as i32)?;
 
     let mut pipeline = pipeline![
-        parallel!(DetectFaces::new(), nthreads),
-        parallel!(DetectEyes::new(), nthreads),
+        parallel!(fallible(DetectFaces::new()), nthreads),
+        parallel!(fallible(DetectEyes::new()), nthreads),
         sequential_ordered!(WriteOutput::new(fps_out,frame_size))
     ];
 
@@ -133,10 +129,17 @@ pub fn rust_spp_eye_tracker(input_video: &String, nthreads: i32) -> opencv::Resu
         if frame.size()?.width == 0 {
             break;
         }
-        pipeline.post(MatData{frame : frame}).unwrap();
+        if pipeline.post(MatData{frame : frame}).is_err() {
+            break;
+        }
     }
 
-    pipeline.end_and_wait();
-
-    Ok(())
+    //Stage errors are opencv errors, anything else is reported as a generic one
+    pipeline.end_and_wait().map_err(|failure| match failure {
+        PipelineError::StageFailed { error, .. } => match error.downcast::<opencv::Error>() {
+            Ok(error) => error,
+            Err(error) => opencv::Error::new(core::StsError, format!("{:?}", error))
+        },
+        failure => opencv::Error::new(core::StsError, failure.to_string())
+    })
 }
\ No newline at end of file
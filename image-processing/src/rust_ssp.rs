@@ -21,35 +21,32 @@ pub fn rust_ssp(dir_name: &str, threads: usize) {
     let start = SystemTime::now();
 
     let pipeline = pipeline![
-            parallel!(move |mut image: Image| {
-                filter::saturation(&mut image, 0.2).unwrap();
-                Some(image)
-            }, threads as i32),
-            parallel!(move |mut image: Image| {
-                filter::emboss(&mut image).unwrap();
-                Some(image)
-            }, threads as i32),
-            parallel!(move |mut image: Image| {
-                filter::gamma(&mut image, 2.0).unwrap();
-                Some(image)
-            }, threads as i32),
-            parallel!(move |mut image: Image| {
-                filter::sharpen(&mut image).unwrap();
-                Some(image)
-            }, threads as i32),
-            parallel!(move |mut image: Image| {
-                filter::grayscale(&mut image).unwrap();
-                Some(image)
-            }, threads as i32),
+            parallel!(fallible(move |mut image: Image| {
+                filter::saturation(&mut image, 0.2).map(|_| Some(image))
+            }), threads as i32),
+            parallel!(fallible(move |mut image: Image| {
+                filter::emboss(&mut image).map(|_| Some(image))
+            }), threads as i32),
+            parallel!(fallible(move |mut image: Image| {
+                filter::gamma(&mut image, 2.0).map(|_| Some(image))
+            }), threads as i32),
+            parallel!(fallible(move |mut image: Image| {
+                filter::sharpen(&mut image).map(|_| Some(image))
+            }), threads as i32),
+            parallel!(fallible(move |mut image: Image| {
+                filter::grayscale(&mut image).map(|_| Some(image))
+            }), threads as i32),
             collect!()
         ];
 
 
     for image in all_images.into_iter() {
-        pipeline.post(image).unwrap();
+        if pipeline.post(image).is_err() {
+            break;
+        }
     }
 
-    let _collection = pipeline.collect();
+    let _collection = pipeline.collect().unwrap();
 
     let system_duration = start.elapsed().expect("Failed to get render time?");
     let in_sec = system_duration.as_secs() as f64 + system_duration.subsec_nanos() as f64 * 1e-9;
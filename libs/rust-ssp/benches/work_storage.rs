@@ -0,0 +1,36 @@
+#[macro_use]
+extern crate criterion;
+extern crate rust_spp;
+
+use criterion::Criterion;
+use rust_spp::*;
+
+//Many tiny items through a farm, so the time goes to the queues
+//and not to the stages
+const ITEMS: u64 = 100_000;
+const REPLICAS: i32 = 8;
+
+fn farm<TBackend: StorageBackend>(backend: TBackend) {
+    let mut pipeline = PipelineBuilder::new()
+        .with_backend(backend)
+        .then_parallel(REPLICAS, || |row: u64| Some(row.wrapping_mul(2654435761)))
+        .then_parallel(REPLICAS, || |row: u64| Some(row >> 3))
+        .sink(|| |_row: u64| ());
+    for row in 0..ITEMS {
+        pipeline.post(row).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+}
+
+fn queue_backends(c: &mut Criterion) {
+    c.bench_function("farm blocking queue", |b| b.iter(|| farm(BlockingBackend)));
+    c.bench_function("farm channel queue", |b| b.iter(|| farm(ChannelBackend)));
+    c.bench_function("farm lock-free queue", |b| b.iter(|| farm(LockFreeBackend)));
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = queue_backends
+}
+criterion_main!(benches);
@@ -1,13 +1,37 @@
-use crate::work_storage::{WorkItem, TimestampedWorkItem};
+use crate::work_storage::{WorkItem, TimestampedWorkItem, ResultQueue};
+use std::sync::Arc;
+use crate::spp::PipelineError;
+use crate::blocks::StageRecorder;
 
 
 //Base trait for all blocks in the pipeline
 //Used by the internals. Should be able to detal with
-//timestamped items and also perform some automatic timestamping on its own
-pub trait PipelineBlock<TInput, TCollected> {
+//timestamped items and also perform some automatic timestamping on its own.
+//Blocks are shared by the threads of the previous stage, hence Send + Sync
+pub trait PipelineBlock<TInput, TCollected>: Send + Sync {
     fn process(&self, input: WorkItem<TInput>);
     fn process_timestamped(&self, input: TimestampedWorkItem<TInput>);
+    //The outputs of a batch, see Batching
+    fn process_batch(&self, inputs: Vec<TimestampedWorkItem<TInput>>) {
+        for input in inputs {
+            self.process_timestamped(input);
+        }
+    }
+    //False for blocks that produce their own input, see SourceBlock
+    fn takes_posts(&self) -> bool {
+        true
+    }
+    //Stops a block that produces its own input before its input runs out
+    fn cancel(&self) {}
     fn collect(self: Box<Self>) -> Vec<TCollected>;
+    //The results of the last block, for consuming them while the stream runs
+    fn results(&self) -> Arc<ResultQueue<TCollected>>;
+    //Failures travel down the pipeline and are kept by the last block
+    fn report_failure(&self, failure: PipelineError);
+    fn has_failed(&self) -> bool;
+    fn take_failure(&self) -> Option<PipelineError>;
+    //Adds the recorders of this block and the ones after it
+    fn recorders(&self, recorders: &mut Vec<Arc<StageRecorder>>);
 }
 
 #[derive(Clone, Copy)]
@@ -18,7 +42,9 @@ pub enum OrderingMode {
 
 pub enum BlockMode {
     Sequential(OrderingMode),
-    Parallel(i32)
+    Parallel(i32),
+    //Replicas run concurrently but their outputs leave the stage in input order
+    ParallelOrdered(i32)
 }
 
 
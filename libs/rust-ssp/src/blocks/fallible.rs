@@ -0,0 +1,166 @@
+use crate::spp::{PipelineError, Hook};
+use std::any::Any;
+use std::error::Error;
+use std::fmt::{self, Debug, Display};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use parking_lot::{Mutex};
+
+//Public API: An error returned by a fallible stage. It is boxed so stages with
+//different error types can live in the same pipeline, downcast gives it back
+pub struct StageError {
+    error: Box<dyn Any + Send + Sync>,
+    debug: fn(&dyn Any, &mut fmt::Formatter) -> fmt::Result
+}
+
+impl StageError {
+    pub fn new<TError>(error: TError) -> StageError
+    where TError: Debug + Send + Sync + 'static {
+        StageError {
+            error: Box::new(error),
+            debug: debug_as::<TError>
+        }
+    }
+
+    pub fn is<TError: 'static>(&self) -> bool {
+        self.error.is::<TError>()
+    }
+
+    pub fn downcast_ref<TError: 'static>(&self) -> Option<&TError> {
+        self.error.downcast_ref::<TError>()
+    }
+
+    //Gives the error back as it was returned, or self if it has another type
+    pub fn downcast<TError: 'static>(self) -> Result<TError, StageError> {
+        let debug = self.debug;
+        match self.error.downcast::<TError>() {
+            Ok(error) => Ok(*error),
+            Err(error) => Err(StageError { error: error, debug: debug })
+        }
+    }
+}
+
+fn debug_as<TError: Debug + 'static>(error: &dyn Any, f: &mut fmt::Formatter) -> fmt::Result {
+    match error.downcast_ref::<TError>() {
+        Some(error) => error.fmt(f),
+        None => f.write_str("StageError")
+    }
+}
+
+impl Debug for StageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (self.debug)(&*self.error, f)
+    }
+}
+
+impl Display for StageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (self.debug)(&*self.error, f)
+    }
+}
+
+impl Error for StageError {}
+
+//Public API: What to do when a fallible stage returns an error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorPolicy {
+    //Stop processing the stream and report the error to the caller
+    FailFast,
+    //Drop the failing item and keep going
+    Skip,
+}
+
+//Public API: Wraps a TryInOut or TryIn stage so it can be given to the stage macros:
+//parallel!(fallible(LoadImage), 4)
+pub struct Fallible<THandler> {
+    pub(crate) handler: THandler,
+    pub(crate) policy: ErrorPolicy,
+}
+
+pub fn fallible<THandler>(handler: THandler) -> Fallible<THandler> {
+    Fallible {
+        handler: handler,
+        policy: ErrorPolicy::FailFast,
+    }
+}
+
+impl<THandler> Fallible<THandler> {
+    pub fn with_policy(mut self, policy: ErrorPolicy) -> Fallible<THandler> {
+        self.policy = policy;
+        self
+    }
+}
+
+//Internals: Runs a stage handler on one item. Errors and panics become the
+//PipelineError of that stage and item, so the replica can keep draining its queue
+pub(crate) fn run_stage<TOutput, F>(stage: usize, order: u64, handler: F) -> Result<Option<TOutput>, PipelineError>
+where F: FnOnce() -> Result<Option<TOutput>, StageError> {
+    match panic::catch_unwind(AssertUnwindSafe(handler)) {
+        Ok(Ok(output)) => Ok(output),
+        Ok(Err(error)) => Err(PipelineError::StageFailed { stage: stage, order: order, error: error }),
+        Err(payload) => Err(PipelineError::StagePanicked {
+            stage: stage,
+            order: order,
+            message: panic_message(payload)
+        })
+    }
+}
+
+//Internals: Runs on_start or on_end. A panic becomes the PipelineError of the hook
+pub(crate) fn run_hook<TOutput, F>(stage: usize, hook: Hook, handler: F) -> Result<TOutput, PipelineError>
+where F: FnOnce() -> TOutput {
+    panic::catch_unwind(AssertUnwindSafe(handler)).map_err(|payload| PipelineError::HookPanicked {
+        stage: stage,
+        hook: hook,
+        message: panic_message(payload)
+    })
+}
+
+//panic!("...") gives a &str payload, panic!("{}", x) gives a String
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => String::from("Box<Any>")
+        }
+    }
+}
+
+//Internals: Keeps the failure of a pipeline. Owned by the last block, other blocks
+//reach it through PipelineBlock::report_failure. If several items fail, the one
+//that came first in the stream wins
+pub struct FailureSlot {
+    failed: AtomicBool,
+    failure: Mutex<Option<PipelineError>>,
+}
+
+impl FailureSlot {
+    pub fn new() -> Arc<FailureSlot> {
+        Arc::new(FailureSlot {
+            failed: AtomicBool::new(false),
+            failure: Mutex::new(None),
+        })
+    }
+
+    pub fn report(&self, failure: PipelineError) {
+        let mut current = self.failure.lock();
+        let replace = match &*current {
+            Some(existing) => failure.position() < existing.position(),
+            None => true
+        };
+        if replace {
+            *current = Some(failure);
+        }
+        self.failed.store(true, Ordering::SeqCst);
+    }
+
+    pub fn has_failed(&self) -> bool {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    pub fn take(&self) -> Option<PipelineError> {
+        self.failure.lock().take()
+    }
+}
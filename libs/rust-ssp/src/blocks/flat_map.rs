@@ -0,0 +1,181 @@
+use crate::blocks::*;
+use crate::work_storage::{WorkItem, TimestampedWorkItem, ResultQueue};
+use crate::spp::PipelineError;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use parking_lot::{Mutex};
+
+//Public API: An Input-Output node that turns a value into any number of outputs
+pub trait FlatInOut<TInput, TOutput> {
+    fn process(&mut self, input: TInput) -> Vec<TOutput>;
+}
+
+
+impl <TInput, TOutput, TOutputs, F> FlatInOut<TInput, TOutput> for F
+where F: FnMut(TInput) -> TOutputs, TOutputs: IntoIterator<Item = TOutput> {
+    fn process(&mut self, input: TInput) -> Vec<TOutput> {
+        (*self)(input).into_iter().collect()
+    }
+}
+
+//Public API: The mode of a flat-map stage. Given by flat_map!
+pub struct FlatMap(pub BlockMode);
+
+//Internals: Runs a FlatInOut as an InOut that outputs the whole sequence.
+//An empty sequence counts as a dropped value
+pub struct FlatMapped<THandler>(pub THandler);
+
+impl<TInput, TOutput, THandler> InOut<TInput, Vec<TOutput>> for FlatMapped<THandler>
+where THandler: FlatInOut<TInput, TOutput> {
+    fn process(&mut self, input: TInput) -> Option<Vec<TOutput>> {
+        let outputs = self.0.process(input);
+        if outputs.is_empty() { None } else { Some(outputs) }
+    }
+}
+
+//Internals: Next input order to release, next output order to give, and the
+//sequences that arrived ahead of their turn
+type Sequences<TOutput> = (u64, u64, BTreeMap<u64, WorkItem<Vec<TOutput>>>);
+
+/*
+ * Internals: Sits between a flat-map stage and the next block. The outputs of an
+ * input are numbered by their position in its sequence, the sub-index. Sequences
+ * are released in the order of their inputs, and each output gets the next order
+ * of the stream after the flat-map: input 0 gives orders 0..n0, input 1 gives
+ * n0..n0+n1 and so on. Stages downstream see a gapless stream and can put it
+ * back in order as usual. Release happens under the lock, like in ReorderBuffer.
+ */
+pub struct Flatten<TOutput, TCollected> {
+    storage: Mutex<Sequences<TOutput>>,
+    next_step: Box<dyn PipelineBlock<TOutput, TCollected>>
+}
+
+impl<TOutput, TCollected> Flatten<TOutput, TCollected> {
+    pub fn new(next_step: Box<dyn PipelineBlock<TOutput, TCollected>>) -> Flatten<TOutput, TCollected> {
+        Flatten {
+            storage: Mutex::new((0, 0, BTreeMap::new())),
+            next_step: next_step
+        }
+    }
+}
+
+//Internals: Adds sequences to the waiting ones and returns the outputs they release
+fn release<TOutput>(
+    storage: &mut (u64, u64, BTreeMap<u64, WorkItem<Vec<TOutput>>>),
+    inputs: Vec<TimestampedWorkItem<Vec<TOutput>>>
+) -> Vec<TimestampedWorkItem<TOutput>> {
+    let (ref mut next_input, ref mut next_output, ref mut waiting) = *storage;
+
+    for TimestampedWorkItem(item, order) in inputs {
+        waiting.insert(order, item);
+    }
+
+    let mut released = vec![];
+    while let Some(item) = waiting.remove(next_input) {
+        *next_input += 1;
+        match item {
+            WorkItem::Value(outputs) => {
+                for output in outputs {
+                    released.push(TimestampedWorkItem(WorkItem::Value(output), *next_output));
+                    *next_output += 1;
+                }
+            }
+            WorkItem::Dropped => {}
+            WorkItem::Stop => released.push(TimestampedWorkItem(WorkItem::Stop, *next_output))
+        }
+    }
+    released
+}
+
+impl<TOutput, TCollected> PipelineBlock<Vec<TOutput>, TCollected> for Flatten<TOutput, TCollected>
+where TOutput: Send {
+    //Only the first block is posted to, and this one always comes after
+    //a flat-map stage. Untimestamped outputs are passed on as they come
+    fn process(&self, input: WorkItem<Vec<TOutput>>) {
+        match input {
+            WorkItem::Value(outputs) => {
+                for output in outputs {
+                    self.next_step.process(WorkItem::Value(output));
+                }
+            }
+            WorkItem::Dropped => {}
+            WorkItem::Stop => self.next_step.process(WorkItem::Stop)
+        }
+    }
+
+    fn process_timestamped(&self, input: TimestampedWorkItem<Vec<TOutput>>) {
+        self.process_batch(vec![input]);
+    }
+
+    //The outputs go out under the lock, so that sequences released by
+    //different replicas reach the next step in order
+    fn process_batch(&self, inputs: Vec<TimestampedWorkItem<Vec<TOutput>>>) {
+        let mut storage = self.storage.lock();
+        let released = release(&mut storage, inputs);
+        if !released.is_empty() {
+            self.next_step.process_batch(released);
+        }
+    }
+
+    fn collect(self: Box<Self>) -> Vec<TCollected> {
+        self.next_step.collect()
+    }
+
+    fn results(&self) -> Arc<ResultQueue<TCollected>> {
+        self.next_step.results()
+    }
+
+    fn report_failure(&self, failure: PipelineError) {
+        self.next_step.report_failure(failure)
+    }
+
+    fn has_failed(&self) -> bool {
+        self.next_step.has_failed()
+    }
+
+    fn take_failure(&self) -> Option<PipelineError> {
+        self.next_step.take_failure()
+    }
+
+    fn recorders(&self, recorders: &mut Vec<Arc<StageRecorder>>) {
+        self.next_step.recorders(recorders)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    //x % 4 copies of x, so some values give an empty sequence
+    fn copies(x: u32) -> Vec<u32> {
+        vec![x; x as usize % 4]
+    }
+
+    #[test]
+    fn outputs_are_numbered_after_the_flat_map() {
+        let pipeline = PipelineBuilder::new()
+            .then_flat_map(3, || copies)
+            .then_sequential_ordered(|| |x: u32, order: u64| Some((order, x)))
+            .sink_ordered(|| |output: (u64, u32)| output);
+        for x in 0..50 {
+            pipeline.post(x).unwrap();
+        }
+        let expected: Vec<u32> = (0..50).flat_map(copies).collect();
+        let collected = pipeline.collect().unwrap();
+        let orders: Vec<u64> = collected.iter().map(|output| output.0).collect();
+        let values: Vec<u32> = collected.iter().map(|output| output.1).collect();
+        assert_eq!(orders, (0..expected.len() as u64).collect::<Vec<u64>>());
+        assert_eq!(values, expected);
+    }
+
+    #[test]
+    fn only_empty_sequences_give_an_empty_stream() {
+        let pipeline = PipelineBuilder::new()
+            .then_flat_map(2, || |_: u32| Vec::<u32>::new())
+            .sink_ordered(|| |x: u32| x);
+        for x in 0..10 {
+            pipeline.post(x).unwrap();
+        }
+        assert!(pipeline.collect().unwrap().is_empty());
+    }
+}
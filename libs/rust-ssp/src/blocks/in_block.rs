@@ -2,15 +2,23 @@ use crate::*;
 use crate::blocks::*;
 use work_storage::{WorkItem, TimestampedWorkItem};
 use std::sync::Arc;
-use std::sync::atomic::{Ordering, AtomicUsize};
-use std::thread::JoinHandle;
-use std::thread;
-use work_storage::{BlockingQueue, BlockingOrderedSet};
+use std::sync::atomic::{Ordering, AtomicU64, AtomicUsize};
+use work_storage::{WorkStorage, Batching, BlockingOrderedSet, ReorderBuffer, ResultQueue};
 use parking_lot::{Mutex};
+use std::fmt::Debug;
+use std::marker::PhantomData;
 
-//Public API: An output node, receives values and causes side effects
+//Public API: An output node, receives values and causes side effects.
+//The hooks are the same as in InOut: on_end can flush, and what it
+//returns is collected after the results of the stream
 pub trait In<TInput, TCollected=()> {
     fn process(&mut self, input: TInput, order: u64) -> TCollected;
+
+    fn on_start(&mut self) {}
+
+    fn on_end(&mut self) -> Vec<TCollected> {
+        vec![]
+    }
 }
 
 
@@ -20,62 +28,245 @@ impl <TInput, TCollected, F> In<TInput, TCollected> for F where F: FnMut(TInput)
     }
 }
 
+//Public API: A fallible output node. Use it through fallible(...)
+pub trait TryIn<TInput, TCollected, TError> {
+    fn process(&mut self, input: TInput, order: u64) -> Result<TCollected, TError>;
+
+    fn on_start(&mut self) {}
+
+    fn on_end(&mut self) -> Vec<TCollected> {
+        vec![]
+    }
+}
+
+
+impl <TInput, TCollected, TError, F> TryIn<TInput, TCollected, TError> for F
+where F: FnMut(TInput) -> Result<TCollected, TError> {
+    fn process(&mut self, input: TInput, _order: u64) -> Result<TCollected, TError> {
+        (*self)(input)
+    }
+}
+
+//Internals: What the replicas of an InBlock run. None means nothing is collected
+pub trait InHandler<TInput, TCollected>: Send {
+    fn handle(&mut self, input: TInput, order: u64) -> Result<Option<TCollected>, StageError>;
+    fn start(&mut self);
+    fn end(&mut self) -> Vec<TCollected>;
+}
+
+//Internals: Same as IntoInOutHandler, for In and Fallible(TryIn)
+pub trait IntoInHandler<TInput, TCollected, TMarker> {
+    fn into_handler(self) -> Box<dyn InHandler<TInput, TCollected>>;
+}
+
+struct InfallibleIn<THandler>(THandler);
+
+impl<TInput, TCollected, THandler> InHandler<TInput, TCollected> for InfallibleIn<THandler>
+where THandler: In<TInput, TCollected> + Send {
+    fn handle(&mut self, input: TInput, order: u64) -> Result<Option<TCollected>, StageError> {
+        Ok(Some(self.0.process(input, order)))
+    }
+
+    fn start(&mut self) {
+        self.0.on_start()
+    }
+
+    fn end(&mut self) -> Vec<TCollected> {
+        self.0.on_end()
+    }
+}
+
+impl<TInput, TCollected, THandler> IntoInHandler<TInput, TCollected, ()> for THandler
+where THandler: In<TInput, TCollected> + Send + 'static {
+    fn into_handler(self) -> Box<dyn InHandler<TInput, TCollected>> {
+        Box::new(InfallibleIn(self))
+    }
+}
+
+struct FallibleIn<THandler, TError>(Fallible<THandler>, PhantomData<fn() -> TError>);
+
+impl<TInput, TCollected, TError, THandler> InHandler<TInput, TCollected> for FallibleIn<THandler, TError>
+where THandler: TryIn<TInput, TCollected, TError> + Send, TError: Debug + Send + Sync + 'static {
+    fn handle(&mut self, input: TInput, order: u64) -> Result<Option<TCollected>, StageError> {
+        match self.0.handler.process(input, order) {
+            Ok(collected) => Ok(Some(collected)),
+            Err(_) if self.0.policy == ErrorPolicy::Skip => Ok(None),
+            Err(error) => Err(StageError::new(error))
+        }
+    }
+
+    fn start(&mut self) {
+        self.0.handler.on_start()
+    }
+
+    fn end(&mut self) -> Vec<TCollected> {
+        self.0.handler.on_end()
+    }
+}
+
+impl<TInput, TCollected, TError, THandler> IntoInHandler<TInput, TCollected, fn() -> TError> for Fallible<THandler>
+where THandler: TryIn<TInput, TCollected, TError> + Send + 'static, TError: Debug + Send + Sync + 'static {
+    fn into_handler(self) -> Box<dyn InHandler<TInput, TCollected>> {
+        Box::new(FallibleIn(self, PhantomData))
+    }
+}
+
+//Internals: Builds the handler of each replica
+type InHandlerFactory<TInput, TCollected> = Box<dyn FnMut() -> Box<dyn InHandler<TInput, TCollected>> + Send>;
+
 //Internals: InBlock processing queue for blocks in the pipeline
 pub struct InBlock<TInput, TCollected> {
-    work_queue: Arc<BlockingQueue<TInput>>,
+    stage: usize,
+    work_queue: Arc<dyn WorkStorage<TInput>>,
     ordered_work: Arc<BlockingOrderedSet<TInput>>,
-    collected_items: Arc<Mutex<Vec<TCollected>>>,
-    handler: Box<FnMut() -> Box<dyn In<TInput, TCollected>>>,
+    results: Arc<ResultQueue<TCollected>>,
+    failure: Arc<FailureSlot>,
+    //Only called from monitor_posts, the lock just makes the block Sync
+    handler: Mutex<InHandlerFactory<TInput, TCollected>>,
     ordering: OrderingMode,
-    counter: AtomicUsize
+    output_order: Option<Arc<ReorderBuffer<TCollected>>>,
+    replicas: i32,
+    batching: Batching,
+    counter: AtomicUsize,
+    metrics: Arc<StageRecorder>
 }
 
 // Internals: This is a thread-local object for in blocks
 struct InBlockInfo<TInput, TCollected> {
-    handler: Box<dyn In<TInput, TCollected>>
+    stage: usize,
+    handler: Box<dyn InHandler<TInput, TCollected>>,
+    failure: Arc<FailureSlot>
+}
+
+impl<TInput, TCollected> InBlockInfo<TInput, TCollected> {
+    //Runs the handler unless the pipeline already failed. A failing or
+    //panicking item is reported and then treated as dropped
+    fn process(&mut self, val: TInput, order: u64) -> Option<TCollected> {
+        if self.failure.has_failed() {
+            return None;
+        }
+        let handler = &mut self.handler;
+        match run_stage(self.stage, order, || handler.handle(val, order)) {
+            Ok(collected) => collected,
+            Err(failure) => {
+                self.failure.report(failure);
+                None
+            }
+        }
+    }
+
+    fn start(&mut self) {
+        let handler = &mut self.handler;
+        if let Err(failure) = run_hook(self.stage, Hook::OnStart, || handler.start()) {
+            self.failure.report(failure);
+        }
+    }
+
+    //Runs on_end once the replica sees Stop. As in InOutBlockInfo, its
+    //results take the orders after the end of the stream
+    fn end(&mut self, stop_order: u64, trailing: &AtomicU64) -> Vec<TimestampedWorkItem<TCollected>> {
+        if self.failure.has_failed() {
+            return vec![];
+        }
+        let handler = &mut self.handler;
+        let collected = match run_hook(self.stage, Hook::OnEnd, || handler.end()) {
+            Ok(collected) => collected,
+            Err(failure) => {
+                self.failure.report(failure);
+                vec![]
+            }
+        };
+
+        let first = stop_order + trailing.fetch_add(collected.len() as u64, Ordering::SeqCst);
+        collected.into_iter().enumerate()
+            .map(|(index, collected)| TimestampedWorkItem(WorkItem::Value(collected), first + index as u64))
+            .collect()
+    }
 }
 
 
-impl <TInput, TCollected> PipelineBlock<TInput, TCollected> for InBlock<TInput, TCollected> {
+impl <TInput, TCollected> PipelineBlock<TInput, TCollected> for InBlock<TInput, TCollected>
+where
+    TInput: Send,
+    TCollected: Send,
+{
 
     //used by the public API
     fn process(&self, input: WorkItem<TInput>) {
         match self.ordering {
             //For the unordered case, just enqueue it
             OrderingMode::Unordered => {
-                (*self.work_queue).enqueue(input);
+                let is_value = matches!(input, WorkItem::Value(_));
+                let order = (*self.work_queue).enqueue(input);
+                if is_value {
+                    self.metrics.trace_enqueue(order);
+                }
             },
-            //For the ordered case: All InBlocks are single threaded
-            //so we keep a count. Store under an atomic counter
-            //in case we implement a multithreaded outblock
+            //For the ordered case we keep a count. Producers can
+            //post from several threads, so it is taken atomically
             OrderingMode::Ordered => {
-                let c = self.counter.load(Ordering::SeqCst);
+                let c = self.counter.fetch_add(1, Ordering::SeqCst);
+                if let WorkItem::Value(_) = input {
+                    self.metrics.trace_enqueue(c as u64);
+                }
                 (*self.ordered_work).enqueue(TimestampedWorkItem(input, c as u64));
-                self.counter.store(c + 1, Ordering::SeqCst);
             }
         };
         ()
     }
 
     //Used internally
+    //The upstream timestamp is kept, so results are tagged with the order
+    //in which their items were posted
     fn process_timestamped(&self, input: TimestampedWorkItem<TInput>) {
+        if let TimestampedWorkItem(WorkItem::Value(_), order) = input {
+            self.metrics.trace_enqueue(order);
+        }
         match self.ordering {
-            OrderingMode::Unordered => match input {
-                TimestampedWorkItem(work_item, _) => {
-                    (*self.work_queue).enqueue(work_item);
-                }
-            },
+            OrderingMode::Unordered => (*self.work_queue).enqueue_timestamped(input),
             OrderingMode::Ordered => (*self.ordered_work).enqueue(input)
         };
     }
 
-    fn collect(self: Box<Self>) -> Vec<TCollected> {
-        match Arc::try_unwrap(self.collected_items) {
-            Ok(result) => result.into_inner(),
-            Err(_) => {
-                panic!("Could not unwrap Arc in call to collect");
+    fn process_batch(&self, inputs: Vec<TimestampedWorkItem<TInput>>) {
+        for input in &inputs {
+            if let TimestampedWorkItem(WorkItem::Value(_), order) = input {
+                self.metrics.trace_enqueue(*order);
             }
         }
+        match self.ordering {
+            OrderingMode::Unordered => (*self.work_queue).enqueue_batch(inputs),
+            OrderingMode::Ordered => {
+                for input in inputs {
+                    (*self.ordered_work).enqueue(input);
+                }
+            }
+        };
+    }
+
+    //Whatever a result receiver did not take yet
+    fn collect(self: Box<Self>) -> Vec<TCollected> {
+        self.results.drain()
+    }
+
+    fn results(&self) -> Arc<ResultQueue<TCollected>> {
+        self.results.clone()
+    }
+
+    fn report_failure(&self, failure: PipelineError) {
+        self.failure.report(failure)
+    }
+
+    fn has_failed(&self) -> bool {
+        self.failure.has_failed()
+    }
+
+    fn take_failure(&self) -> Option<PipelineError> {
+        self.failure.take()
+    }
+
+    fn recorders(&self, recorders: &mut Vec<Arc<StageRecorder>>) {
+        recorders.push(self.metrics.clone());
     }
 }
 
@@ -84,71 +275,139 @@ impl <TInput, TCollected> PipelineBlock<TInput, TCollected> for InBlock<TInput,
 impl<TInput: 'static, TCollected: 'static> InBlock<TInput, TCollected>
 where
     TInput: Send,
-    TInput: Sync,
     TCollected: Send,
-    TCollected: Sync,
 {
-    pub fn monitor_posts(&mut self) -> MonitorLoop {
+    pub fn monitor_posts(&mut self) -> Vec<MonitorLoop> {
         match self.ordering {
-            OrderingMode::Ordered => self.monitor_ordered(),
+            OrderingMode::Ordered => vec![self.monitor_ordered()],
             OrderingMode::Unordered => self.monitor_unordered()
         }
         
     }
 
-    fn monitor_unordered(&mut self) -> MonitorLoop {
-        let queue = self.work_queue.clone();
+    fn monitor_unordered(&mut self) -> Vec<MonitorLoop> {
+        let mut monitors: Vec<MonitorLoop> = vec![];
+        let alive_threads = Arc::new(AtomicUsize::new(self.replicas as usize));
+        let trailing = Arc::new(AtomicU64::new(0));
 
-        let mut info = InBlockInfo {
-            handler: (self.handler)()
-        };
+        for replica in 0..self.replicas as usize {
+            let queue = self.work_queue.clone();
+            let alive_threads = alive_threads.clone();
+            let trailing = trailing.clone();
+            let metrics = self.metrics.clone();
 
-        let arc_collected = self.collected_items.clone();
+            let mut info = InBlockInfo {
+                stage: self.stage,
+                handler: (self.handler.get_mut())(),
+                failure: self.failure.clone()
+            };
 
-        MonitorLoop::new(move || {
-            let mut collected_list = arc_collected.lock();
-            loop {
-                let item = queue.wait_and_dequeue();
-                match item {
-                    TimestampedWorkItem(WorkItem::Value(val), order) => {
-                        let collected: TCollected = info.handler.process(val, order);
-                        (*collected_list).push(collected);
-                    },
-                    TimestampedWorkItem(WorkItem::Dropped, order) => {
-                        ()
-                    }
-                    TimestampedWorkItem(WorkItem::Stop, _) => {
-                        break;
-                    }
+            let results = self.results.clone();
+            let output_order = self.output_order.clone();
+            let batching = self.batching;
+
+            monitors.push(MonitorLoop::new(move || {
+                //Ordered farms only hand a result over once the
+                //reorder window releases it
+                let emit_ordered = |item: TimestampedWorkItem<TCollected>| {
+                    results.push(item);
                 };
-            }
-        })
+                info.start();
+                'replica: loop {
+                    let batch = metrics.idle(replica, || queue.wait_and_dequeue_batch(batching));
+                    metrics.sample_depth(|| queue.len());
+                    metrics.batch(replica, batch.len());
+                    for item in batch {
+                        match item {
+                            TimestampedWorkItem(WorkItem::Value(val), order) => metrics.busy(replica, order, || {
+                                let collected = info.process(val, order);
+                                let produced = collected.is_some();
+                                match (&output_order, collected) {
+                                    (Some(reorder), Some(collected)) => reorder.push(
+                                        TimestampedWorkItem(WorkItem::Value(collected), order), &emit_ordered),
+                                    (Some(reorder), None) => reorder.push(
+                                        TimestampedWorkItem(WorkItem::Dropped, order), &emit_ordered),
+                                    (None, Some(collected)) => results.push(
+                                        TimestampedWorkItem(WorkItem::Value(collected), order)),
+                                    (None, None) => results.push(
+                                        TimestampedWorkItem(WorkItem::Dropped, order))
+                                }
+                                produced
+                            }),
+                            TimestampedWorkItem(WorkItem::Dropped, order) => {
+                                match &output_order {
+                                    Some(reorder) => reorder.push(
+                                        TimestampedWorkItem(WorkItem::Dropped, order), &emit_ordered),
+                                    None => results.push(TimestampedWorkItem(WorkItem::Dropped, order))
+                                }
+                            }
+                            TimestampedWorkItem(WorkItem::Stop, order) => {
+                                for collected in info.end(order, &trailing) {
+                                    match &output_order {
+                                        Some(reorder) => reorder.push(collected, &emit_ordered),
+                                        None => results.push(collected)
+                                    }
+                                }
+                                //The last replica to stop ends the results
+                                if alive_threads.fetch_sub(1, Ordering::SeqCst) == 1 {
+                                    results.end();
+                                }
+                                //reenqueue the same item so the other replicas stop too
+                                queue.enqueue_timestamped(item);
+                                break 'replica;
+                            }
+                        };
+                    }
+                }
+            }));
+        }
+
+        monitors
     }
 
     pub fn monitor_ordered(&mut self) -> MonitorLoop {
         let storage = self.ordered_work.clone();
         
         let mut info = InBlockInfo {
-            handler: (self.handler)()
+            stage: self.stage,
+            handler: (self.handler.get_mut())(),
+            failure: self.failure.clone()
         };
-        let arc_collected = self.collected_items.clone();
+        let results = self.results.clone();
+        let metrics = self.metrics.clone();
 
         MonitorLoop::new(move || {
+            info.start();
+            let trailing = AtomicU64::new(0);
             let mut next_item = 0;
-            let mut collected_list = arc_collected.lock();
             loop {
-                let item = storage.wait_and_remove(next_item);
+                let item = metrics.idle(0, || storage.wait_and_remove(next_item));
+                metrics.sample_depth(|| storage.len());
+                metrics.batch(0, 1);
                 match item {
                     TimestampedWorkItem(WorkItem::Value(val), order) => {
                         debug_assert!(order == next_item);
                         next_item += 1;
-                        let collected: TCollected = info.handler.process(val, order);
-                        (*collected_list).push(collected);
+                        metrics.busy(0, order, || match info.process(val, order) {
+                            Some(collected) => {
+                                results.push(TimestampedWorkItem(WorkItem::Value(collected), order));
+                                true
+                            }
+                            None => {
+                                results.push(TimestampedWorkItem(WorkItem::Dropped, order));
+                                false
+                            }
+                        });
                     }
                     TimestampedWorkItem(WorkItem::Dropped, order) => {
                         next_item += 1;
+                        results.push(TimestampedWorkItem(WorkItem::Dropped, order));
                     }
-                    TimestampedWorkItem(WorkItem::Stop, _) => {
+                    TimestampedWorkItem(WorkItem::Stop, order) => {
+                        for collected in info.end(order, &trailing) {
+                            results.push(collected);
+                        }
+                        results.end();
                         break;
                     }
                 };
@@ -160,20 +419,104 @@ where
 
 
 impl<TInput, TCollected> InBlock<TInput, TCollected> {
-    pub fn new(behavior: BlockMode, factory: Box<FnMut() -> Box<dyn In<TInput, TCollected>>>) -> InBlock<TInput, TCollected> {
+    //The work queue is only used by unordered blocks. Ordered blocks must accept
+    //any item that arrives ahead of the one they wait for, so they keep an unbounded set
+    pub fn new<TFactory, THandler, TMarker>(
+        stage: usize,
+        behavior: BlockMode,
+        mut factory: TFactory,
+        work_queue: Arc<dyn WorkStorage<TInput>>,
+        batching: Batching
+    ) -> InBlock<TInput, TCollected>
+    where
+        TFactory: FnMut() -> THandler + Send + 'static,
+        THandler: IntoInHandler<TInput, TCollected, TMarker>,
+    {
+        let handler: InHandlerFactory<TInput, TCollected> =
+            Box::new(move || factory().into_handler());
         match behavior {
-            BlockMode::Parallel(_) => unimplemented!("parallel inblocks not implemented"),
-            BlockMode::Sequential(ordering) => InBlock {
-                work_queue: BlockingQueue::new(),
-                handler: factory,
-                ordering: ordering,
-                ordered_work: BlockingOrderedSet::new(),
-                counter: AtomicUsize::new(0),
-                collected_items: Arc::new(Mutex::new(vec![]))
-            },
+            //Parallel replicas each own a handler and pull from the same queue,
+            //so the collected results come out unordered
+            BlockMode::Parallel(replicas) => {
+                InBlock::new_block(stage, handler, OrderingMode::Unordered, replicas, work_queue, batching)
+            }
+            //Same as above, but results are collected in input order
+            BlockMode::ParallelOrdered(replicas) => {
+                let mut block = InBlock::new_block(stage, handler, OrderingMode::Unordered, replicas, work_queue, batching);
+                block.output_order = Some(ReorderBuffer::new());
+                block
+            }
+            BlockMode::Sequential(ordering) => InBlock::new_block(stage, handler, ordering, 1, work_queue, batching),
+        }
+    }
+
+    fn new_block(
+        stage: usize,
+        handler: InHandlerFactory<TInput, TCollected>,
+        ordering: OrderingMode,
+        replicas: i32,
+        work_queue: Arc<dyn WorkStorage<TInput>>,
+        batching: Batching
+    ) -> InBlock<TInput, TCollected> {
+        InBlock {
+            stage: stage,
+            work_queue: work_queue,
+            handler: Mutex::new(handler),
+            ordering: ordering,
+            output_order: None,
+            replicas: replicas,
+            batching: batching,
+            ordered_work: BlockingOrderedSet::new(),
+            counter: AtomicUsize::new(0),
+            results: ResultQueue::new(),
+            metrics: StageRecorder::new(stage, replicas as usize),
+            failure: FailureSlot::new()
         }
     }
 }
 
-unsafe impl<TInput, TCollected> Send for InBlockInfo<TInput, TCollected> {}
-unsafe impl<TInput, TCollected> Sync for InBlockInfo<TInput, TCollected> {}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    //Collects its values and gives 1000 + their sum from on_end
+    struct Total(u32);
+
+    impl In<u32, u32> for Total {
+        fn process(&mut self, x: u32, _order: u64) -> u32 {
+            self.0 += x;
+            x
+        }
+
+        fn on_end(&mut self) -> Vec<u32> {
+            vec![1000 + self.0]
+        }
+    }
+
+    #[test]
+    fn on_end_results_are_collected_after_the_stream() {
+        let pipeline = PipelineBuilder::new()
+            .then_parallel_ordered(2, || |x: u32| Some(x))
+            .sink_ordered(|| Total(0));
+        for x in 0..10 {
+            pipeline.post(x).unwrap();
+        }
+        let mut expected: Vec<u32> = (0..10).collect();
+        expected.push(1045);
+        assert_eq!(pipeline.collect().unwrap(), expected);
+    }
+
+    #[test]
+    fn every_replica_of_a_sink_runs_on_end() {
+        let pipeline = PipelineBuilder::new().sink_parallel(2, || Total(0));
+        for x in 0..10 {
+            pipeline.post(x).unwrap();
+        }
+        let mut collected = pipeline.collect().unwrap();
+        collected.sort();
+        assert_eq!(collected.len(), 12);
+        assert_eq!(&collected[..10], &(0..10).collect::<Vec<u32>>()[..]);
+        assert_eq!(collected[10] + collected[11] - 2000, 45);
+    }
+}
@@ -1,13 +1,25 @@
 use crate::blocks::*;
 use crate::work_storage::*;
+use crate::spp::{PipelineError, Hook};
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::mem;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::thread::JoinHandle;
-use std::thread;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use parking_lot::{Mutex};
 
-// Public API: A Input-Output node; transforms some value into another
+// Public API: A Input-Output node; transforms some value into another.
+// on_start runs on the thread of each replica before its first value, and
+// on_end once the replica sees the end of the stream. The outputs of on_end
+// go downstream before the end of the stream does
 pub trait InOut<TInput, TOutput> {
     fn process(&mut self, input: TInput) -> Option<TOutput>;
+
+    fn on_start(&mut self) {}
+
+    fn on_end(&mut self) -> Vec<TOutput> {
+        vec![]
+    }
 }
 
 
@@ -17,24 +29,285 @@ impl <TInput, TOutput, F> InOut<TInput, TOutput> for F where F: FnMut(TInput) ->
     }
 }
 
+// Public API: An Input-Output node that also gets the order of the value, its
+// position in the stream that reaches the stage. As in In, the first value
+// posted has order 0. Closures take it as a second argument
+pub trait IndexedInOut<TInput, TOutput> {
+    fn process(&mut self, input: TInput, order: u64) -> Option<TOutput>;
+
+    fn on_start(&mut self) {}
+
+    fn on_end(&mut self) -> Vec<TOutput> {
+        vec![]
+    }
+}
+
+
+impl <TInput, TOutput, F> IndexedInOut<TInput, TOutput> for F where F: FnMut(TInput, u64) -> Option<TOutput> {
+    fn process(&mut self, input: TInput, order: u64) -> Option<TOutput> {
+        (*self)(input, order)
+    }
+}
+
+// Public API: A fallible Input-Output node. Use it through fallible(...)
+pub trait TryInOut<TInput, TOutput, TError> {
+    fn process(&mut self, input: TInput) -> Result<Option<TOutput>, TError>;
+
+    fn on_start(&mut self) {}
+
+    fn on_end(&mut self) -> Vec<TOutput> {
+        vec![]
+    }
+}
+
+
+impl <TInput, TOutput, TError, F> TryInOut<TInput, TOutput, TError> for F
+where F: FnMut(TInput) -> Result<Option<TOutput>, TError> {
+    fn process(&mut self, input: TInput) -> Result<Option<TOutput>, TError> {
+        (*self)(input)
+    }
+}
+
+// Internals: What the replicas of an InOutBlock run. Errors that get here
+// fail the pipeline, skipped ones are already turned into None.
+// Each replica moves its handler to its own thread
+pub trait InOutHandler<TInput, TOutput>: Send {
+    fn handle(&mut self, input: TInput, order: u64) -> Result<Option<TOutput>, StageError>;
+    fn start(&mut self);
+    fn end(&mut self) -> Vec<TOutput>;
+}
+
+// Internals: Turns whatever a stage factory returns into a handler. The marker
+// type only tells apart the impls for InOut, IndexedInOut and Fallible(TryInOut)
+pub trait IntoInOutHandler<TInput, TOutput, TMarker> {
+    fn into_handler(self) -> Box<dyn InOutHandler<TInput, TOutput>>;
+}
+
+struct InfallibleInOut<THandler>(THandler);
+
+impl<TInput, TOutput, THandler> InOutHandler<TInput, TOutput> for InfallibleInOut<THandler>
+where THandler: InOut<TInput, TOutput> + Send {
+    fn handle(&mut self, input: TInput, _order: u64) -> Result<Option<TOutput>, StageError> {
+        Ok(self.0.process(input))
+    }
+
+    fn start(&mut self) {
+        self.0.on_start()
+    }
+
+    fn end(&mut self) -> Vec<TOutput> {
+        self.0.on_end()
+    }
+}
+
+impl<TInput, TOutput, THandler> IntoInOutHandler<TInput, TOutput, ()> for THandler
+where THandler: InOut<TInput, TOutput> + Send + 'static {
+    fn into_handler(self) -> Box<dyn InOutHandler<TInput, TOutput>> {
+        Box::new(InfallibleInOut(self))
+    }
+}
+
+struct IndexedHandler<THandler>(THandler);
+
+impl<TInput, TOutput, THandler> InOutHandler<TInput, TOutput> for IndexedHandler<THandler>
+where THandler: IndexedInOut<TInput, TOutput> + Send {
+    fn handle(&mut self, input: TInput, order: u64) -> Result<Option<TOutput>, StageError> {
+        Ok(self.0.process(input, order))
+    }
+
+    fn start(&mut self) {
+        self.0.on_start()
+    }
+
+    fn end(&mut self) -> Vec<TOutput> {
+        self.0.on_end()
+    }
+}
+
+impl<TInput, TOutput, THandler> IntoInOutHandler<TInput, TOutput, fn(u64)> for THandler
+where THandler: IndexedInOut<TInput, TOutput> + Send + 'static {
+    fn into_handler(self) -> Box<dyn InOutHandler<TInput, TOutput>> {
+        Box::new(IndexedHandler(self))
+    }
+}
+
+struct FallibleInOut<THandler, TError>(Fallible<THandler>, PhantomData<fn() -> TError>);
+
+impl<TInput, TOutput, TError, THandler> InOutHandler<TInput, TOutput> for FallibleInOut<THandler, TError>
+where THandler: TryInOut<TInput, TOutput, TError> + Send, TError: Debug + Send + Sync + 'static {
+    fn handle(&mut self, input: TInput, _order: u64) -> Result<Option<TOutput>, StageError> {
+        match self.0.handler.process(input) {
+            Ok(output) => Ok(output),
+            Err(_) if self.0.policy == ErrorPolicy::Skip => Ok(None),
+            Err(error) => Err(StageError::new(error))
+        }
+    }
+
+    fn start(&mut self) {
+        self.0.handler.on_start()
+    }
+
+    fn end(&mut self) -> Vec<TOutput> {
+        self.0.handler.on_end()
+    }
+}
+
+impl<TInput, TOutput, TError, THandler> IntoInOutHandler<TInput, TOutput, fn() -> TError> for Fallible<THandler>
+where THandler: TryInOut<TInput, TOutput, TError> + Send + 'static, TError: Debug + Send + Sync + 'static {
+    fn into_handler(self) -> Box<dyn InOutHandler<TInput, TOutput>> {
+        Box::new(FallibleInOut(self, PhantomData))
+    }
+}
+
 
 // Internals: This is a thread-local object for inout blocks
 struct InOutBlockInfo<TInput, TOutput, TCollected> {
+    stage: usize,
+    replica: usize,
+    metrics: Arc<StageRecorder>,
     next_step: Arc<Box<dyn PipelineBlock<TOutput, TCollected>>>,
-    transformer: Box<dyn InOut<TInput, TOutput>>
+    output_order: Option<Arc<ReorderBuffer<TOutput>>>,
+    transformer: Box<dyn InOutHandler<TInput, TOutput>>
 }
 
+impl<TInput, TOutput, TCollected> InOutBlockInfo<TInput, TOutput, TCollected> {
+    //Transforms a value, keeping its order. When the transformer discards the
+    //value or fails, the output is a Dropped marker so that ordered stages
+    //downstream don't wait for it
+    fn process(&mut self, val: TInput, order: u64) -> TimestampedWorkItem<TOutput> {
+        //Once the pipeline failed the remaining items are only drained
+        let output = if self.next_step.has_failed() {
+            None
+        } else {
+            let transformer = &mut self.transformer;
+            match run_stage(self.stage, order, || transformer.handle(val, order)) {
+                Ok(output) => output,
+                Err(failure) => {
+                    self.next_step.report_failure(failure);
+                    None
+                }
+            }
+        };
+        self.metrics.trace_processed(self.replica);
+
+        match output {
+            Some(val) => TimestampedWorkItem(WorkItem::Value(val), order),
+            None => TimestampedWorkItem(WorkItem::Dropped, order)
+        }
+    }
+
+    //Runs on_start. If it fails the pipeline fails, and the replica
+    //drains its queue like after any failure
+    fn start(&mut self) {
+        let transformer = &mut self.transformer;
+        if let Err(failure) = run_hook(self.stage, Hook::OnStart, || transformer.start()) {
+            self.next_step.report_failure(failure);
+        }
+    }
+
+    //Runs on_end once the replica sees Stop. Its outputs take the orders after
+    //the end of the stream, in the order the replicas of the stage end, so
+    //Stop has to be forwarded with the number of them that were given
+    fn end(&mut self, stop_order: u64, trailing: &AtomicU64) {
+        let outputs = if self.next_step.has_failed() {
+            vec![]
+        } else {
+            let transformer = &mut self.transformer;
+            match run_hook(self.stage, Hook::OnEnd, || transformer.end()) {
+                Ok(outputs) => outputs,
+                Err(failure) => {
+                    self.next_step.report_failure(failure);
+                    vec![]
+                }
+            }
+        };
+        if outputs.is_empty() {
+            return;
+        }
+
+        let first = stop_order + trailing.fetch_add(outputs.len() as u64, Ordering::SeqCst);
+        let outputs = outputs.into_iter().enumerate()
+            .map(|(index, output)| TimestampedWorkItem(WorkItem::Value(output), first + index as u64))
+            .collect();
+        self.forward_batch(outputs);
+    }
+
+    //Returns whether a value was forwarded
+    fn process_and_forward(&mut self, val: TInput, order: u64) -> bool {
+        let output = self.process(val, order);
+        let forwarded = matches!(output.0, WorkItem::Value(_));
+        self.forward(output);
+        forwarded
+    }
+
+    //Ordered farms go through the shared reorder window, everything else
+    //goes straight to the next step
+    fn forward(&self, item: TimestampedWorkItem<TOutput>) {
+        let next_step = &self.next_step;
+        match &self.output_order {
+            Some(reorder) => reorder.push(item, |item| next_step.process_timestamped(item)),
+            None => next_step.process_timestamped(item)
+        }
+    }
+
+    fn forward_batch(&self, items: Vec<TimestampedWorkItem<TOutput>>) {
+        let next_step = &self.next_step;
+        match &self.output_order {
+            Some(reorder) => reorder.push_batch(items, |items| next_step.process_batch(items)),
+            None => next_step.process_batch(items)
+        }
+    }
+}
+
+//Internals: Builds the handler of each replica
+type InOutHandlerFactory<TInput, TOutput> = Box<dyn FnMut() -> Box<dyn InOutHandler<TInput, TOutput>> + Send>;
+
 //Internals: Processing queue for inout blocks in the pipeline
 pub struct InOutBlock<TInput, TOutput, TCollected> {
-    work_queue: Arc<BlockingQueue<TInput>>,
+    stage: usize,
+    work_queue: Arc<dyn WorkStorage<TInput>>,
+    ordered_work: Arc<BlockingOrderedSet<TInput>>,
     next_step: Arc<Box<dyn PipelineBlock<TOutput, TCollected>>>,
-    transformer_factory: Box<FnMut() -> Box<dyn InOut<TInput, TOutput>>>,
+    //Only called from monitor_posts, the lock just makes the block Sync
+    transformer_factory: Mutex<InOutHandlerFactory<TInput, TOutput>>,
+    ordering: OrderingMode,
+    output_order: Option<Arc<ReorderBuffer<TOutput>>>,
+    //Keyed farms give each replica its own queue
+    router: Option<Arc<KeyRouter<TInput>>>,
     replicas: i32,
+    batching: Batching,
+    counter: AtomicUsize,
+    metrics: Arc<StageRecorder>
 }
 
 impl<TInput, TOutput, TCollected> InOutBlock<TInput, TOutput, TCollected> {
     pub fn send_stop(&self) {
-        (*self.work_queue).enqueue(WorkItem::Stop);
+        self.enqueue(WorkItem::Stop);
+    }
+
+    //Items posted from the outside have no timestamp yet. The ordered case
+    //stamps them with a counter, the same way ordered InBlocks do. Producers
+    //can post from several threads, so the counter is taken atomically
+    fn enqueue(&self, input: WorkItem<TInput>) {
+        match self.ordering {
+            OrderingMode::Unordered => {
+                let is_value = matches!(input, WorkItem::Value(_));
+                let order = match &self.router {
+                    Some(router) => router.post(input, &|failure| self.next_step.report_failure(failure)),
+                    None => (*self.work_queue).enqueue(input)
+                };
+                if is_value {
+                    self.metrics.trace_enqueue(order);
+                }
+            },
+            OrderingMode::Ordered => {
+                let c = self.counter.fetch_add(1, Ordering::SeqCst);
+                if let WorkItem::Value(_) = input {
+                    self.metrics.trace_enqueue(c as u64);
+                }
+                (*self.ordered_work).enqueue(TimestampedWorkItem(input, c as u64));
+            }
+        };
     }
 }
 
@@ -42,16 +315,46 @@ impl<TInput: 'static, TCollected: 'static, TOutput: 'static> PipelineBlock<TInpu
 for InOutBlock<TInput, TOutput, TCollected>
 where
     TInput: Send,
-    TInput: Sync,
+    TOutput: Send,
 {
-    //used by the public API. Always unordered
+    //used by the public API
     fn process(&self, input: WorkItem<TInput>) {
-        (*self.work_queue).enqueue(input);
+        self.enqueue(input);
     }
 
     //Used internally
     fn process_timestamped(&self, input: TimestampedWorkItem<TInput>) {
-        (*self.work_queue).enqueue_timestamped(input)
+        if let TimestampedWorkItem(WorkItem::Value(_), order) = input {
+            self.metrics.trace_enqueue(order);
+        }
+        match (self.ordering, &self.router) {
+            (OrderingMode::Unordered, Some(router)) => {
+                router.route(input, &|failure| self.next_step.report_failure(failure))
+            }
+            (OrderingMode::Unordered, None) => (*self.work_queue).enqueue_timestamped(input),
+            (OrderingMode::Ordered, _) => (*self.ordered_work).enqueue(input)
+        };
+    }
+
+    fn process_batch(&self, inputs: Vec<TimestampedWorkItem<TInput>>) {
+        for input in &inputs {
+            if let TimestampedWorkItem(WorkItem::Value(_), order) = input {
+                self.metrics.trace_enqueue(*order);
+            }
+        }
+        match (self.ordering, &self.router) {
+            (OrderingMode::Unordered, Some(router)) => {
+                for input in inputs {
+                    router.route(input, &|failure| self.next_step.report_failure(failure));
+                }
+            }
+            (OrderingMode::Unordered, None) => (*self.work_queue).enqueue_batch(inputs),
+            (OrderingMode::Ordered, _) => {
+                for input in inputs {
+                    (*self.ordered_work).enqueue(input);
+                }
+            }
+        };
     }
 
     fn collect(self: Box<Self>) -> Vec<TCollected> {
@@ -63,99 +366,191 @@ where
         }
     }
 
+    fn results(&self) -> Arc<ResultQueue<TCollected>> {
+        self.next_step.results()
+    }
+
+    fn report_failure(&self, failure: PipelineError) {
+        self.next_step.report_failure(failure)
+    }
+
+    fn has_failed(&self) -> bool {
+        self.next_step.has_failed()
+    }
+
+    fn take_failure(&self) -> Option<PipelineError> {
+        self.next_step.take_failure()
+    }
+
+    fn recorders(&self, recorders: &mut Vec<Arc<StageRecorder>>) {
+        recorders.push(self.metrics.clone());
+        self.next_step.recorders(recorders)
+    }
+
 }
 
 impl<TInput: 'static, TOutput: 'static, TCollected: 'static> InOutBlock<TInput, TOutput, TCollected>
 where
     TInput: Send,
-    TInput: Sync,
+    TOutput: Send,
 {
-    pub fn new(
+    pub fn new<TFactory, THandler, TMarker>(
+        stage: usize,
         next_step: Box<dyn PipelineBlock<TOutput, TCollected>>,
         transformer: BlockMode,
-        transformer_factory: Box<FnMut() -> Box<dyn InOut<TInput, TOutput>>>
-    ) -> InOutBlock<TInput, TOutput, TCollected> {
+        mut factory: TFactory,
+        work_queue: Arc<dyn WorkStorage<TInput>>,
+        batching: Batching
+    ) -> InOutBlock<TInput, TOutput, TCollected>
+    where
+        TFactory: FnMut() -> THandler + Send + 'static,
+        THandler: IntoInOutHandler<TInput, TOutput, TMarker>,
+    {
+        let transformer_factory: InOutHandlerFactory<TInput, TOutput> =
+            Box::new(move || factory().into_handler());
         match transformer {
             BlockMode::Parallel(replicas) => {
-                InOutBlock::new_block(next_step, transformer_factory, replicas)
+                InOutBlock::new_block(stage, next_step, transformer_factory, OrderingMode::Unordered, replicas, work_queue, batching)
+            }
+            BlockMode::ParallelOrdered(replicas) => {
+                let mut block = InOutBlock::new_block(
+                    stage, next_step, transformer_factory, OrderingMode::Unordered, replicas, work_queue, batching);
+                block.output_order = Some(ReorderBuffer::new());
+                block
+            }
+            BlockMode::Sequential(ordering) => {
+                InOutBlock::new_block(stage, next_step, transformer_factory, ordering, 1, work_queue, batching)
             }
-            BlockMode::Sequential(_) => InOutBlock::new_block(next_step, transformer_factory, 1),
         }
     }
    
+    //As in InBlock, ordered blocks don't use the work queue
     pub fn new_block(
+        stage: usize,
         next_step: Box<dyn PipelineBlock<TOutput, TCollected>>,
-        transformer: Box<FnMut() -> Box<dyn InOut<TInput, TOutput>>>,
+        transformer: InOutHandlerFactory<TInput, TOutput>,
+        ordering: OrderingMode,
         replicas: i32,
+        work_queue: Arc<dyn WorkStorage<TInput>>,
+        batching: Batching,
     ) -> InOutBlock<TInput, TOutput, TCollected> {
         InOutBlock {
-            work_queue: BlockingQueue::new(),
+            stage: stage,
+            work_queue: work_queue,
+            ordered_work: BlockingOrderedSet::new(),
             next_step: Arc::new(next_step),
-            transformer_factory: transformer,
+            transformer_factory: Mutex::new(transformer),
+            ordering: ordering,
+            output_order: None,
+            router: None,
             replicas: replicas,
+            batching: batching,
+            counter: AtomicUsize::new(0),
+            metrics: StageRecorder::new(stage, replicas as usize)
         }
     }
 
+    //Makes the block a keyed farm. The router needs a queue per replica
+    pub fn route_by_key(&mut self, router: KeyRouter<TInput>) {
+        assert_eq!(router.replicas(), self.replicas as usize, "A keyed farm needs a queue per replica");
+        self.router = Some(Arc::new(router));
+    }
 
     pub fn monitor_posts(&mut self) -> Vec<MonitorLoop> {
+        match self.ordering {
+            OrderingMode::Ordered => vec![self.monitor_ordered()],
+            OrderingMode::Unordered => self.monitor_unordered()
+        }
+    }
+
+    fn monitor_unordered(&mut self) -> Vec<MonitorLoop> {
         let mut monitors: Vec<MonitorLoop> = vec![];
         let alive_threads = Arc::new(AtomicUsize::new(self.replicas as usize));
+        let trailing = Arc::new(AtomicU64::new(0));
 
-        for _ in 0..self.replicas {
-            let queue = self.work_queue.clone();
+        for replica in 0..self.replicas as usize {
+            let queue = match &self.router {
+                Some(router) => router.queue(replica),
+                None => self.work_queue.clone()
+            };
+            let router = self.router.clone();
             let alive_threads = alive_threads.clone();
+            let trailing = trailing.clone();
+            let metrics = self.metrics.clone();
+            let batching = self.batching;
             
             let mut info = InOutBlockInfo {
+                stage: self.stage,
+                replica: replica,
+                metrics: metrics.clone(),
                 next_step: self.next_step.clone(),
-                transformer: (self.transformer_factory)(),
+                output_order: self.output_order.clone(),
+                transformer: (self.transformer_factory.get_mut())(),
             };
             
             let monitor_loop = MonitorLoop::new(move || {
-               
+                info.start();
+
                 loop {
-                    let dequeued = queue.wait_and_dequeue();
+                    let batch = metrics.idle(replica, || queue.wait_and_dequeue_batch(batching));
+                    metrics.sample_depth(|| match &router {
+                        Some(router) => router.len(),
+                        None => queue.len()
+                    });
+                    metrics.batch(replica, batch.len());
 
-                    match dequeued {
-                        TimestampedWorkItem(WorkItem::Value(val), order) => {
-                            let output = info.transformer.process(val);
+                    //The outputs go out together once the last value of the batch
+                    //is processed, so the forwarding counts towards that value
+                    let last_value = batch.iter().rposition(|item| matches!(item.0, WorkItem::Value(_)));
+                    let mut outputs = Vec::with_capacity(batch.len());
+                    let mut stop = None;
 
-                            if let Some(val) = output {
-                                info.next_step.process_timestamped(TimestampedWorkItem(
-                                    WorkItem::Value(val),
-                                    order,
-                                ));
-                            } else {
-                                info.next_step.process_timestamped(TimestampedWorkItem(
+                    for (index, dequeued) in batch.into_iter().enumerate() {
+                        match dequeued {
+                            TimestampedWorkItem(WorkItem::Value(val), order) => {
+                                metrics.busy(replica, order, || {
+                                    let output = info.process(val, order);
+                                    let produced = matches!(output.0, WorkItem::Value(_));
+                                    outputs.push(output);
+                                    if Some(index) == last_value {
+                                        info.forward_batch(mem::take(&mut outputs));
+                                    }
+                                    produced
+                                });
+                            },
+                            TimestampedWorkItem(WorkItem::Dropped, order) => {
+                                outputs.push(TimestampedWorkItem(
                                     WorkItem::Dropped,
                                     order,
                                 ));
+                            },
+                            //Always the last item of a batch
+                            TimestampedWorkItem(WorkItem::Stop, order) => {
+                                stop = Some(order);
                             }
-                        },
-                        TimestampedWorkItem(WorkItem::Dropped, order) => {
-                            info.next_step.process_timestamped(TimestampedWorkItem(
-                                WorkItem::Dropped,
-                                order,
-                            ));
-                        },
-                        TimestampedWorkItem(WorkItem::Stop, order) => {
-                            let mut threads = alive_threads.load(Ordering::SeqCst);
-                        
-                            threads -= 1;
-
-                            if threads == 0 {
-                                info.next_step.process_timestamped(TimestampedWorkItem(
-                                    WorkItem::Stop,
-                                    order,
-                                ));
-                            }
+                        }
+                    }
 
-                            alive_threads.store(threads, Ordering::SeqCst);
+                    if !outputs.is_empty() {
+                        info.forward_batch(outputs);
+                    }
 
-                            //reenqueue the same item
-                            queue.enqueue_timestamped(dequeued);
+                    if let Some(order) = stop {
+                        info.end(order, &trailing);
 
-                            break;
+                        //The last replica to end forwards Stop, after
+                        //the outputs of every on_end
+                        if alive_threads.fetch_sub(1, Ordering::SeqCst) == 1 {
+                            info.forward(TimestampedWorkItem(
+                                WorkItem::Stop,
+                                order + trailing.load(Ordering::SeqCst),
+                            ));
                         }
+
+                        //reenqueue the same item
+                        queue.enqueue_timestamped(TimestampedWorkItem(WorkItem::Stop, order));
+
+                        break;
                     }
                 }
             });
@@ -165,8 +560,125 @@ where
         return monitors;
     }
 
+    //Sequential stage that sees items in the order they were posted,
+    //regardless of the order in which the previous stage produced them
+    fn monitor_ordered(&mut self) -> MonitorLoop {
+        let storage = self.ordered_work.clone();
+        let metrics = self.metrics.clone();
+
+        let mut info = InOutBlockInfo {
+            stage: self.stage,
+            replica: 0,
+            metrics: metrics.clone(),
+            next_step: self.next_step.clone(),
+            output_order: None,
+            transformer: (self.transformer_factory.get_mut())(),
+        };
+
+        MonitorLoop::new(move || {
+            info.start();
+            let trailing = AtomicU64::new(0);
+            let mut next_item = 0;
+            loop {
+                let item = metrics.idle(0, || storage.wait_and_remove(next_item));
+                metrics.sample_depth(|| storage.len());
+                metrics.batch(0, 1);
+                match item {
+                    TimestampedWorkItem(WorkItem::Value(val), order) => {
+                        debug_assert!(order == next_item);
+                        next_item += 1;
+                        metrics.busy(0, order, || info.process_and_forward(val, order));
+                    }
+                    TimestampedWorkItem(WorkItem::Dropped, order) => {
+                        next_item += 1;
+                        info.forward(TimestampedWorkItem(
+                            WorkItem::Dropped,
+                            order,
+                        ));
+                    }
+                    TimestampedWorkItem(WorkItem::Stop, order) => {
+                        info.end(order, &trailing);
+                        info.forward(TimestampedWorkItem(
+                            WorkItem::Stop,
+                            order + trailing.load(Ordering::SeqCst),
+                        ));
+                        break;
+                    }
+                };
+            }
+        })
+    }
+
 }
 
-/* Assume a MapBlock can be passed to threads, and assume we'll implement parallelism correctly */
-unsafe impl<TInput, TOutput, TCollected> Send for InOutBlockInfo<TInput, TOutput, TCollected> {}
-unsafe impl<TInput, TOutput, TCollected> Sync for InOutBlockInfo<TInput, TOutput, TCollected> {}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::thread;
+    use std::time::Duration;
+
+    //Later values finish first, so the replicas give their results out of order
+    fn uneven(x: u32) -> Option<u32> {
+        thread::sleep(Duration::from_micros(((20 - x % 20) * 50) as u64));
+        Some(x)
+    }
+
+    #[test]
+    fn ordered_farms_give_their_outputs_in_input_order() {
+        let pipeline = PipelineBuilder::new()
+            .then_parallel_ordered(4, || uneven)
+            .then_parallel_ordered(3, || |x: u32| uneven(x).map(|x| x * 2))
+            .sink(|| |x: u32| x);
+        for x in 0..100 {
+            pipeline.post(x).unwrap();
+        }
+        let expected: Vec<u32> = (0..100).map(|x| x * 2).collect();
+        assert_eq!(pipeline.collect().unwrap(), expected);
+    }
+
+    #[test]
+    fn dropped_values_do_not_stall_an_ordered_farm() {
+        let pipeline = PipelineBuilder::new()
+            .then_parallel_ordered(4, || |x: u32| if x.is_multiple_of(2) { uneven(x) } else { None })
+            .sink(|| |x: u32| x);
+        for x in 0..100 {
+            pipeline.post(x).unwrap();
+        }
+        let expected: Vec<u32> = (0..100).filter(|x| x % 2 == 0).collect();
+        assert_eq!(pipeline.collect().unwrap(), expected);
+    }
+
+    //Counts its values and gives the count from on_end
+    struct Count(u32);
+
+    impl InOut<u32, u32> for Count {
+        fn process(&mut self, x: u32) -> Option<u32> {
+            self.0 += 1;
+            Some(x)
+        }
+
+        fn on_end(&mut self) -> Vec<u32> {
+            vec![1000 + self.0]
+        }
+    }
+
+    #[test]
+    fn on_end_outputs_take_the_orders_after_the_stream() {
+        let pipeline = PipelineBuilder::new()
+            .then_parallel(3, || Count(0))
+            .then_sequential_ordered(|| |x: u32, order: u64| Some((order, x)))
+            .sink_ordered(|| |output: (u64, u32)| output);
+        for x in 0..50 {
+            pipeline.post(x).unwrap();
+        }
+        let collected = pipeline.collect().unwrap();
+        let orders: Vec<u64> = collected.iter().map(|output| output.0).collect();
+        assert_eq!(orders, (0..53).collect::<Vec<u64>>());
+
+        let values: Vec<u32> = collected.iter().map(|output| output.1).collect();
+        assert_eq!(&values[..50], &(0..50).collect::<Vec<u32>>()[..]);
+        assert!(values[50..].iter().all(|count| *count >= 1000));
+        assert_eq!(values[50..].iter().map(|count| count - 1000).sum::<u32>(), 50);
+    }
+}
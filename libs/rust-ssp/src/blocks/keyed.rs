@@ -0,0 +1,150 @@
+use crate::blocks::fallible::run_stage;
+use crate::spp::PipelineError;
+use crate::work_storage::*;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+//Public API: The mode of a keyed farm. Given by keyed!
+pub struct Keyed<TKeyFn> {
+    pub replicas: i32,
+    pub key: TKeyFn
+}
+
+impl<TKeyFn> Keyed<TKeyFn> {
+    pub fn new(replicas: i32, key: TKeyFn) -> Keyed<TKeyFn> {
+        Keyed {
+            replicas: replicas,
+            key: key
+        }
+    }
+}
+
+/*
+ * Internals: Routes the items of a keyed farm. Each replica has its own queue, and
+ * the hash of the key of a value picks the queue. Items are put back in stream
+ * order before they are routed, so each replica sees the values of its keys in
+ * the order they were posted. Dropped markers go to any queue, their replica
+ * just forwards them. Stop goes to every queue. The key runs on the thread that
+ * routes the item, so a panic in it fails the stage and the item is routed as
+ * Dropped.
+ */
+pub struct KeyRouter<TInput> {
+    stage: usize,
+    key: Box<dyn Fn(&TInput) -> u64 + Send + Sync>,
+    queues: Vec<Arc<dyn WorkStorage<TInput>>>,
+    input_order: Arc<ReorderBuffer<TInput>>,
+    //Stamps the items posted to the farm, when it is the first stage
+    number_of_posts: AtomicU64
+}
+
+impl<TInput> KeyRouter<TInput> {
+    pub fn new<TKey, TKeyFn>(stage: usize, key: TKeyFn, queues: Vec<Arc<dyn WorkStorage<TInput>>>) -> KeyRouter<TInput>
+    where
+        TKey: Hash,
+        TKeyFn: Fn(&TInput) -> TKey + Send + Sync + 'static {
+        assert!(!queues.is_empty(), "A keyed farm needs at least one replica");
+        KeyRouter {
+            stage: stage,
+            key: Box::new(move |input| {
+                let mut hasher = DefaultHasher::new();
+                key(input).hash(&mut hasher);
+                hasher.finish()
+            }),
+            queues: queues,
+            input_order: ReorderBuffer::new(),
+            number_of_posts: AtomicU64::new(0)
+        }
+    }
+
+    pub fn replicas(&self) -> usize {
+        self.queues.len()
+    }
+
+    pub fn queue(&self, replica: usize) -> Arc<dyn WorkStorage<TInput>> {
+        self.queues[replica].clone()
+    }
+
+    //Items waiting in every queue
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(|queue| queue.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(|queue| queue.is_empty())
+    }
+
+    pub fn post(&self, item: WorkItem<TInput>, report_failure: &dyn Fn(PipelineError)) -> u64 {
+        let order = self.number_of_posts.fetch_add(1, Ordering::SeqCst);
+        self.route(TimestampedWorkItem(item, order), report_failure);
+        order
+    }
+
+    pub fn route(&self, item: TimestampedWorkItem<TInput>, report_failure: &dyn Fn(PipelineError)) {
+        let stage = self.stage;
+        let queues = &self.queues;
+        let key = &self.key;
+        self.input_order.push(item, |item| {
+            let (replica, item) = match item {
+                TimestampedWorkItem(WorkItem::Value(value), order) => {
+                    match run_stage(stage, order, || Ok(Some(key(&value)))) {
+                        Ok(hash) => (hash.unwrap_or(order), TimestampedWorkItem(WorkItem::Value(value), order)),
+                        Err(failure) => {
+                            report_failure(failure);
+                            (order, TimestampedWorkItem(WorkItem::Dropped, order))
+                        }
+                    }
+                }
+                TimestampedWorkItem(WorkItem::Dropped, order) => (order, TimestampedWorkItem(WorkItem::Dropped, order)),
+                TimestampedWorkItem(WorkItem::Stop, order) => {
+                    for queue in queues {
+                        queue.enqueue_timestamped(TimestampedWorkItem(WorkItem::Stop, order));
+                    }
+                    return;
+                }
+            };
+            queues[(replica % queues.len() as u64) as usize].enqueue_timestamped(item);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn values_of_a_key_keep_their_order() {
+        let pipeline = PipelineBuilder::new()
+            .then_keyed(3, |x: &u32| x % 5, || |x: u32| Some(x))
+            .sink(|| |x: u32| x);
+        for x in 0..200 {
+            pipeline.post(x).unwrap();
+        }
+        let collected = pipeline.collect().unwrap();
+        assert_eq!(collected.len(), 200);
+        for key in 0..5 {
+            let values: Vec<u32> = collected.iter().cloned().filter(|x| x % 5 == key).collect();
+            let expected: Vec<u32> = (0..200).filter(|x| x % 5 == key).collect();
+            assert_eq!(values, expected);
+        }
+    }
+
+    #[test]
+    fn a_panic_in_the_key_fails_the_keyed_stage() {
+        let pipeline = PipelineBuilder::new()
+            .then_keyed(3, |x: &u32| if *x == 7 { panic!("no key") } else { x % 5 }, || |x: u32| Some(x))
+            .sink(|| |x: u32| x);
+        for x in 0..20 {
+            let _ = pipeline.post(x);
+        }
+        match pipeline.collect() {
+            Err(PipelineError::StagePanicked { stage, order, message }) => {
+                assert_eq!(stage, 0);
+                assert_eq!(order, 7);
+                assert_eq!(message, "no key");
+            }
+            other => panic!("Unexpected result {:?}", other)
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use parking_lot::{Mutex};
+
+//Queue depth is sampled at most this often, so long runs keep a small history
+const DEPTH_SAMPLE_INTERVAL: Duration = Duration::from_millis(10);
+
+//Public API: Snapshot of one stage, taken with Pipeline::metrics()
+#[derive(Clone, Debug)]
+pub struct StageMetrics {
+    pub stage: usize,
+    //Values the stage received
+    pub items_in: u64,
+    //Values the stage forwarded, or collected if it is the last stage
+    pub items_out: u64,
+    //Values the stage turned into Dropped markers: filtered, skipped or failed
+    pub dropped: u64,
+    //Dequeues done by the replicas and the items they took. Without
+    //batching each dequeue takes a single item
+    pub batches: u64,
+    pub batched_items: u64,
+    pub replicas: Vec<ReplicaMetrics>,
+    //Items waiting in the stage queue, sampled every few milliseconds.
+    //The time is counted from the creation of the stage
+    pub queue_depth: Vec<(Duration, usize)>
+}
+
+#[derive(Clone, Debug)]
+pub struct ReplicaMetrics {
+    pub items_in: u64,
+    //Time spent on items, forwarding included
+    pub busy: Duration,
+    //Time spent waiting for items
+    pub idle: Duration
+}
+
+impl StageMetrics {
+    pub fn busy(&self) -> Duration {
+        self.replicas.iter().map(|replica| replica.busy).sum()
+    }
+
+    pub fn idle(&self) -> Duration {
+        self.replicas.iter().map(|replica| replica.idle).sum()
+    }
+
+    pub fn mean_batch_size(&self) -> f64 {
+        if self.batches == 0 {
+            0.0
+        } else {
+            self.batched_items as f64 / self.batches as f64
+        }
+    }
+}
+
+//Internals: What a replica did with one value, recorded when tracing
+#[derive(Clone, Copy)]
+pub struct ItemSpan {
+    pub order: u64,
+    //Items of a batch share their dequeue, and start one after the other
+    pub dequeued: Instant,
+    pub started: Instant,
+    pub processed: Instant,
+    pub forwarded: Instant
+}
+
+//Internals: Counters of one replica. Only its own thread writes them
+struct ReplicaCounters {
+    items_in: AtomicU64,
+    items_out: AtomicU64,
+    dropped: AtomicU64,
+    batches: AtomicU64,
+    batched_items: AtomicU64,
+    busy_nanos: AtomicU64,
+    idle_nanos: AtomicU64,
+    //When the current batch was dequeued, when the current value was done
+    //processing, and the spans so far
+    trace: Mutex<(Option<Instant>, Option<Instant>, Vec<ItemSpan>)>
+}
+
+//Internals: Shared by the replicas of a block, read by Pipeline::metrics()
+//and by the trace export. Tracing is off until Pipeline::enable_tracing()
+pub struct StageRecorder {
+    stage: usize,
+    created: Instant,
+    replicas: Vec<ReplicaCounters>,
+    next_depth_sample: AtomicU64,
+    queue_depth: Mutex<Vec<(Duration, usize)>>,
+    tracing: AtomicBool,
+    enqueued: Mutex<Vec<(u64, Instant)>>
+}
+
+impl StageRecorder {
+    pub fn new(stage: usize, replicas: usize) -> Arc<StageRecorder> {
+        Arc::new(StageRecorder {
+            stage: stage,
+            created: Instant::now(),
+            replicas: (0..replicas).map(|_| ReplicaCounters {
+                items_in: AtomicU64::new(0),
+                items_out: AtomicU64::new(0),
+                dropped: AtomicU64::new(0),
+                batches: AtomicU64::new(0),
+                batched_items: AtomicU64::new(0),
+                busy_nanos: AtomicU64::new(0),
+                idle_nanos: AtomicU64::new(0),
+                trace: Mutex::new((None, None, vec![]))
+            }).collect(),
+            next_depth_sample: AtomicU64::new(0),
+            queue_depth: Mutex::new(vec![]),
+            tracing: AtomicBool::new(false),
+            enqueued: Mutex::new(vec![])
+        })
+    }
+
+    pub fn stage(&self) -> usize {
+        self.stage
+    }
+
+    pub fn enable_tracing(&self) {
+        self.tracing.store(true, Ordering::SeqCst);
+    }
+
+    fn is_tracing(&self) -> bool {
+        self.tracing.load(Ordering::Relaxed)
+    }
+
+    //A value with this order was put in the stage queue
+    pub fn trace_enqueue(&self, order: u64) {
+        if self.is_tracing() {
+            self.enqueued.lock().push((order, Instant::now()));
+        }
+    }
+
+    //Called between processing a value and forwarding it. Stages that
+    //don't call it get a span with no forwarding time
+    pub fn trace_processed(&self, replica: usize) {
+        if self.is_tracing() {
+            self.replicas[replica].trace.lock().1 = Some(Instant::now());
+        }
+    }
+
+    //Waits for the next item, counting the wait as idle time. When tracing,
+    //the end of the wait is the dequeue of the values that busy processes next
+    pub fn idle<T, F: FnOnce() -> T>(&self, replica: usize, wait: F) -> T {
+        let start = Instant::now();
+        let item = wait();
+        let end = Instant::now();
+        add_nanos(&self.replicas[replica].idle_nanos, end - start);
+        if self.is_tracing() {
+            self.replicas[replica].trace.lock().0 = Some(end);
+        }
+        item
+    }
+
+    //A dequeue that took this many items
+    pub fn batch(&self, replica: usize, items: usize) {
+        let counters = &self.replicas[replica];
+        counters.batches.fetch_add(1, Ordering::Relaxed);
+        counters.batched_items.fetch_add(items as u64, Ordering::Relaxed);
+    }
+
+    //Processes one value. The closure tells whether it produced an output
+    pub fn busy<F: FnOnce() -> bool>(&self, replica: usize, order: u64, work: F) {
+        let counters = &self.replicas[replica];
+        let start = Instant::now();
+        counters.items_in.fetch_add(1, Ordering::Relaxed);
+        if work() {
+            counters.items_out.fetch_add(1, Ordering::Relaxed);
+        } else {
+            counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        let end = Instant::now();
+        add_nanos(&counters.busy_nanos, end - start);
+        self.trace_span(counters, order, start, end);
+    }
+
+    //Produces one value of a source. The closure tells whether there was one.
+    //The last call, which finds none, counts as busy time but not as a value
+    pub fn produce<F: FnOnce() -> bool>(&self, replica: usize, order: u64, work: F) -> bool {
+        let counters = &self.replicas[replica];
+        let start = Instant::now();
+        let produced = work();
+        let end = Instant::now();
+        add_nanos(&counters.busy_nanos, end - start);
+        if produced {
+            counters.items_in.fetch_add(1, Ordering::Relaxed);
+            counters.items_out.fetch_add(1, Ordering::Relaxed);
+            self.trace_span(counters, order, start, end);
+        }
+        produced
+    }
+
+    //Sources dequeue nothing, their values count as dequeued once produced
+    fn trace_span(&self, counters: &ReplicaCounters, order: u64, start: Instant, end: Instant) {
+        if self.is_tracing() {
+            let mut trace = counters.trace.lock();
+            let dequeued = trace.0.unwrap_or(start);
+            let processed = trace.1.take().unwrap_or(end);
+            trace.2.push(ItemSpan {
+                order: order,
+                dequeued: dequeued,
+                started: start,
+                processed: processed,
+                forwarded: end
+            });
+        }
+    }
+
+    //The closure reads the queue, it is only called when a sample is due
+    pub fn sample_depth<F: FnOnce() -> usize>(&self, depth: F) {
+        let now = self.created.elapsed();
+        let now_nanos = now.as_nanos() as u64;
+        let due = self.next_depth_sample.load(Ordering::Relaxed);
+        if now_nanos < due {
+            return;
+        }
+        let next = now_nanos + DEPTH_SAMPLE_INTERVAL.as_nanos() as u64;
+        //Only one replica takes each sample
+        if self.next_depth_sample.compare_exchange(due, next, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+            self.queue_depth.lock().push((now, depth()));
+        }
+    }
+
+    pub fn snapshot(&self) -> StageMetrics {
+        let replicas: Vec<ReplicaMetrics> = self.replicas.iter().map(|counters| ReplicaMetrics {
+            items_in: counters.items_in.load(Ordering::Relaxed),
+            busy: Duration::from_nanos(counters.busy_nanos.load(Ordering::Relaxed)),
+            idle: Duration::from_nanos(counters.idle_nanos.load(Ordering::Relaxed))
+        }).collect();
+
+        StageMetrics {
+            stage: self.stage,
+            items_in: replicas.iter().map(|replica| replica.items_in).sum(),
+            items_out: self.replicas.iter().map(|counters| counters.items_out.load(Ordering::Relaxed)).sum(),
+            dropped: self.replicas.iter().map(|counters| counters.dropped.load(Ordering::Relaxed)).sum(),
+            batches: self.replicas.iter().map(|counters| counters.batches.load(Ordering::Relaxed)).sum(),
+            batched_items: self.replicas.iter().map(|counters| counters.batched_items.load(Ordering::Relaxed)).sum(),
+            replicas: replicas,
+            queue_depth: self.queue_depth.lock().clone()
+        }
+    }
+
+    //When each value was enqueued, and the spans of each replica
+    pub fn trace(&self) -> (Vec<(u64, Instant)>, Vec<Vec<ItemSpan>>) {
+        let enqueued = self.enqueued.lock().clone();
+        let spans = self.replicas.iter().map(|counters| counters.trace.lock().2.clone()).collect();
+        (enqueued, spans)
+    }
+}
+
+fn add_nanos(counter: &AtomicU64, elapsed: Duration) {
+    counter.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blocks::StageRecorder;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn values_of_a_batch_share_their_dequeue() {
+        let recorder = StageRecorder::new(0, 1);
+        recorder.enable_tracing();
+        recorder.idle(0, || thread::sleep(Duration::from_millis(1)));
+        recorder.batch(0, 2);
+        for order in 0..2 {
+            recorder.busy(0, order, || {
+                thread::sleep(Duration::from_millis(5));
+                true
+            });
+        }
+
+        let (_, spans) = recorder.trace();
+        let spans = &spans[0];
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].dequeued, spans[1].dequeued);
+        assert!(spans[0].dequeued <= spans[0].started);
+        assert!(spans[1].started >= spans[0].forwarded);
+        assert!(spans[1].started - spans[1].dequeued >= Duration::from_millis(5));
+    }
+}
@@ -1,8 +1,27 @@
 
 pub mod blocks;
+pub mod fallible;
+pub mod flat_map;
 pub mod in_block;
 pub mod inout_block;
+pub mod keyed;
+pub mod metrics;
+pub mod nested;
+pub mod scatter_gather;
+pub mod source;
+pub mod trace;
+pub mod window;
 
 pub use blocks::{BlockMode, OrderingMode, PipelineBlock, MonitorLoop};
-pub use in_block::{In, InBlock};
-pub use inout_block::{InOut, InOutBlock};
\ No newline at end of file
+pub use fallible::{fallible, Fallible, ErrorPolicy, StageError, FailureSlot};
+pub(crate) use fallible::{run_stage, run_hook};
+pub use flat_map::{FlatInOut, FlatMap, FlatMapped, Flatten};
+pub use in_block::{In, TryIn, InHandler, IntoInHandler, InBlock};
+pub use inout_block::{InOut, IndexedInOut, TryInOut, InOutHandler, IntoInOutHandler, InOutBlock};
+pub use keyed::{Keyed, KeyRouter};
+pub use metrics::{StageMetrics, ReplicaMetrics, StageRecorder};
+pub use nested::{Nested, NestedFarm};
+pub use scatter_gather::{ScatterGather, SubTask, ScatterGatherMode};
+pub(crate) use scatter_gather::{PartPool, Scattering};
+pub use source::{Out, Source, SourceBlock};
+pub use window::{Window, WindowSpan, Reduce, WindowBlock};
\ No newline at end of file
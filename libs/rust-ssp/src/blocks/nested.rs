@@ -0,0 +1,373 @@
+use crate::blocks::*;
+use crate::builder::PipelineBuilder;
+use crate::work_storage::*;
+use crate::spp::PipelineError;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use parking_lot::{Mutex};
+
+//Public API: The mode of a stage whose replicas are whole pipelines, without a sink.
+//Given by nested!, each replica is built by its own call to the factory. With more
+//than one replica, the stages must give one output per value: flat_map! and
+//window! stages can't be nested in a farm
+pub struct Nested<TInput, TOutput, TCollected, TBackend> {
+    pub(crate) copies: Vec<PipelineBuilder<TInput, TOutput, TCollected, TBackend>>
+}
+
+impl<TInput, TOutput, TCollected, TBackend> Nested<TInput, TOutput, TCollected, TBackend> {
+    pub fn new<TFactory>(replicas: i32, mut factory: TFactory) -> Nested<TInput, TOutput, TCollected, TBackend>
+    where TFactory: FnMut() -> PipelineBuilder<TInput, TOutput, TCollected, TBackend> {
+        assert!(replicas > 0, "A nested stage needs at least one replica");
+        let copies: Vec<_> = (0..replicas).map(|_| factory()).collect();
+        assert!(replicas == 1 || copies.iter().all(|copy| copy.one_to_one),
+            "Stages nested in a farm must give one output per value, flat_map! and window! can't be replicated");
+        Nested {
+            copies: copies
+        }
+    }
+}
+
+//Internals: The next order a copy gives, and the farm order of each item in the copy
+type CopyOrders = Arc<Mutex<(u64, HashMap<u64, u64>)>>;
+
+//Internals: Shared by the outputs of the copies. The last copy to stop
+//forwards the Stop the farm got, after the outputs of on_end hooks
+struct FarmEnd<TOutput, TCollected> {
+    next_step: Box<dyn PipelineBlock<TOutput, TCollected>>,
+    running_copies: AtomicUsize,
+    stop_order: AtomicU64,
+    trailing: AtomicU64
+}
+
+/*
+ * Internals: The first block of a stage whose replicas are copies of a pipeline.
+ * Values go to the copies in turn. Each copy numbers the values it gets from 0
+ * with no gaps, so that the ordered stages inside it work as in a pipeline of
+ * their own. The copy outputs get back the order of their values, which is why
+ * nested stages must give one output, or Dropped marker, per value.
+ * Dropped markers skip the copies.
+ */
+pub struct NestedFarm<TInput, TOutput, TCollected> {
+    copies: Vec<Box<dyn PipelineBlock<TInput, TCollected>>>,
+    orders: Vec<CopyOrders>,
+    next_copy: AtomicUsize,
+    counter: AtomicU64,
+    end: Arc<FarmEnd<TOutput, TCollected>>
+}
+
+impl<TInput: 'static, TOutput: 'static, TCollected: 'static> NestedFarm<TInput, TOutput, TCollected>
+where
+    TInput: Send,
+    TOutput: Send,
+{
+    //The closure builds a copy, given the block its outputs go to
+    pub fn new<F>(
+        next_step: Box<dyn PipelineBlock<TOutput, TCollected>>,
+        copies: usize,
+        mut build_copy: F
+    ) -> NestedFarm<TInput, TOutput, TCollected>
+    where F: FnMut(Box<dyn PipelineBlock<TOutput, TCollected>>) -> Box<dyn PipelineBlock<TInput, TCollected>> {
+        let end = Arc::new(FarmEnd {
+            next_step: next_step,
+            running_copies: AtomicUsize::new(copies),
+            stop_order: AtomicU64::new(0),
+            trailing: AtomicU64::new(0)
+        });
+        let orders: Vec<CopyOrders> = (0..copies).map(|_| Arc::new(Mutex::new((0, HashMap::new())))).collect();
+        let copies = orders.iter().map(|orders| build_copy(Box::new(CopyOutput {
+            orders: orders.clone(),
+            end: end.clone()
+        }))).collect();
+
+        NestedFarm {
+            copies: copies,
+            orders: orders,
+            next_copy: AtomicUsize::new(0),
+            counter: AtomicU64::new(0),
+            end: end
+        }
+    }
+}
+
+impl<TInput: 'static, TOutput: 'static, TCollected: 'static> PipelineBlock<TInput, TCollected>
+for NestedFarm<TInput, TOutput, TCollected>
+where
+    TInput: Send,
+    TOutput: Send,
+{
+    //Items posted from the outside are stamped with a counter
+    fn process(&self, input: WorkItem<TInput>) {
+        let order = self.counter.fetch_add(1, Ordering::SeqCst);
+        self.process_timestamped(TimestampedWorkItem(input, order));
+    }
+
+    fn process_timestamped(&self, input: TimestampedWorkItem<TInput>) {
+        match input {
+            TimestampedWorkItem(WorkItem::Value(value), order) => {
+                let copy = self.next_copy.fetch_add(1, Ordering::Relaxed) % self.copies.len();
+                let copy_order = {
+                    let mut orders = self.orders[copy].lock();
+                    let copy_order = orders.0;
+                    orders.0 += 1;
+                    orders.1.insert(copy_order, order);
+                    copy_order
+                };
+                self.copies[copy].process_timestamped(TimestampedWorkItem(WorkItem::Value(value), copy_order));
+            }
+            TimestampedWorkItem(WorkItem::Dropped, order) => {
+                self.end.next_step.process_timestamped(TimestampedWorkItem(WorkItem::Dropped, order));
+            }
+            //Comes after every value, each copy gets the number of values it had
+            TimestampedWorkItem(WorkItem::Stop, order) => {
+                self.end.stop_order.store(order, Ordering::SeqCst);
+                for (copy, orders) in self.copies.iter().zip(&self.orders) {
+                    let copy_order = orders.lock().0;
+                    copy.process_timestamped(TimestampedWorkItem(WorkItem::Stop, copy_order));
+                }
+            }
+        }
+    }
+
+    //The copies only hold the end of the farm until their threads are done
+    fn collect(self: Box<Self>) -> Vec<TCollected> {
+        let NestedFarm { copies, end, .. } = *self;
+        drop(copies);
+        match Arc::try_unwrap(end) {
+            Ok(end) => end.next_step.collect(),
+            Err(_) => {
+                panic!("Could not unwrap Arc in call to collect");
+            }
+        }
+    }
+
+    fn results(&self) -> Arc<ResultQueue<TCollected>> {
+        self.end.next_step.results()
+    }
+
+    fn report_failure(&self, failure: PipelineError) {
+        self.end.next_step.report_failure(failure)
+    }
+
+    fn has_failed(&self) -> bool {
+        self.end.next_step.has_failed()
+    }
+
+    fn take_failure(&self) -> Option<PipelineError> {
+        self.end.next_step.take_failure()
+    }
+
+    //The stages of every copy, then the ones after the farm
+    fn recorders(&self, recorders: &mut Vec<Arc<StageRecorder>>) {
+        for copy in &self.copies {
+            copy.recorders(recorders);
+        }
+        self.end.next_step.recorders(recorders)
+    }
+}
+
+//Internals: Comes after the last stage of a copy, gives its outputs
+//back the farm order of their values
+struct CopyOutput<TOutput, TCollected> {
+    orders: CopyOrders,
+    end: Arc<FarmEnd<TOutput, TCollected>>
+}
+
+impl<TOutput, TCollected> CopyOutput<TOutput, TCollected> {
+    fn stop(&self) {
+        if self.end.running_copies.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let order = self.end.stop_order.load(Ordering::SeqCst) + self.end.trailing.load(Ordering::SeqCst);
+            self.end.next_step.process_timestamped(TimestampedWorkItem(WorkItem::Stop, order));
+        }
+    }
+}
+
+impl<TOutput, TCollected> PipelineBlock<TOutput, TCollected> for CopyOutput<TOutput, TCollected>
+where TOutput: Send {
+    //Only the first block is posted to. Untimestamped outputs are passed on as they come
+    fn process(&self, input: WorkItem<TOutput>) {
+        match input {
+            WorkItem::Stop => self.stop(),
+            input => self.end.next_step.process(input)
+        }
+    }
+
+    fn process_timestamped(&self, input: TimestampedWorkItem<TOutput>) {
+        match input {
+            TimestampedWorkItem(WorkItem::Stop, _) => self.stop(),
+            TimestampedWorkItem(item, copy_order) => {
+                let order = self.orders.lock().1.remove(&copy_order);
+                //The outputs of on_end come after the values of the copy, and are
+                //numbered after the end of the stream the farm got
+                let order = match order {
+                    Some(order) => order,
+                    None => {
+                        let stop_order = self.end.stop_order.load(Ordering::SeqCst);
+                        stop_order + self.end.trailing.fetch_add(1, Ordering::SeqCst)
+                    }
+                };
+                self.end.next_step.process_timestamped(TimestampedWorkItem(item, order));
+            }
+        }
+    }
+
+    //Collected through the farm, which holds the next step too
+    fn collect(self: Box<Self>) -> Vec<TCollected> {
+        vec![]
+    }
+
+    fn results(&self) -> Arc<ResultQueue<TCollected>> {
+        self.end.next_step.results()
+    }
+
+    //The item is still known to the copy, its output comes after the failure
+    fn report_failure(&self, failure: PipelineError) {
+        let order = failure.order().and_then(|order| self.orders.lock().1.get(&order).cloned());
+        match order {
+            Some(order) => self.end.next_step.report_failure(failure.with_order(order)),
+            None => self.end.next_step.report_failure(failure)
+        }
+    }
+
+    fn has_failed(&self) -> bool {
+        self.end.next_step.has_failed()
+    }
+
+    fn take_failure(&self) -> Option<PipelineError> {
+        self.end.next_step.take_failure()
+    }
+
+    //Added by the farm, after every copy
+    fn recorders(&self, _recorders: &mut Vec<Arc<StageRecorder>>) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    fn doubled() -> PipelineBuilder<u32, u32, u32> {
+        PipelineBuilder::new().then_sequential_ordered(|| |x: u32| Some(x * 2))
+    }
+
+    #[test]
+    fn farm_outputs_keep_the_order_of_their_values() {
+        let pipeline = PipelineBuilder::new()
+            .then_nested(3, doubled)
+            .sink_ordered(|| |x: u32| x);
+        for x in 0..100 {
+            pipeline.post(x).unwrap();
+        }
+        let expected: Vec<u32> = (0..100).map(|x| x * 2).collect();
+        assert_eq!(pipeline.collect().unwrap(), expected);
+    }
+
+    #[test]
+    fn dropped_values_end_the_stream_after_the_farm() {
+        let pipeline = PipelineBuilder::new()
+            .then_nested(2, || PipelineBuilder::new()
+                .then_parallel(2, || |x: u32| if x.is_multiple_of(3) { Some(x) } else { None }))
+            .sink_ordered(|| |x: u32| x);
+        for x in 0..30 {
+            pipeline.post(x).unwrap();
+        }
+        let expected: Vec<u32> = (0..30).filter(|x| x % 3 == 0).collect();
+        assert_eq!(pipeline.collect().unwrap(), expected);
+    }
+
+    #[test]
+    fn failures_in_a_copy_get_the_order_of_the_farm() {
+        let pipeline = PipelineBuilder::new()
+            .then_nested(2, || PipelineBuilder::new()
+                .then_sequential(|| fallible(|x: u32| if x == 7 { Err("seven") } else { Ok(Some(x)) })))
+            .sink(|| |x: u32| x);
+        for x in 0..20 {
+            pipeline.post(x).unwrap();
+        }
+        match pipeline.collect() {
+            Err(failure) => {
+                assert_eq!(failure.stage(), 0);
+                assert_eq!(failure.order(), Some(7));
+            }
+            Ok(_) => panic!("The pipeline should fail")
+        }
+    }
+
+    //Counts its values and gives the count from on_end
+    struct Count(u32);
+
+    impl InOut<u32, u32> for Count {
+        fn process(&mut self, x: u32) -> Option<u32> {
+            self.0 += 1;
+            Some(x)
+        }
+
+        fn on_end(&mut self) -> Vec<u32> {
+            vec![1000 + self.0]
+        }
+    }
+
+    #[test]
+    fn hooks_run_in_every_copy_of_a_farm() {
+        let pipeline = PipelineBuilder::new()
+            .then_nested(2, || PipelineBuilder::new().then_sequential(|| Count(0)))
+            .sink_ordered(|| |x: u32| x);
+        for x in 0..10 {
+            pipeline.post(x).unwrap();
+        }
+        let collected = pipeline.collect().unwrap();
+        assert_eq!(&collected[..10], &(0..10).collect::<Vec<u32>>()[..]);
+        let mut counts = collected[10..].to_vec();
+        counts.sort();
+        assert_eq!(counts, vec![1005, 1005]);
+    }
+
+    struct FailToStart;
+
+    impl InOut<u32, u32> for FailToStart {
+        fn process(&mut self, x: u32) -> Option<u32> {
+            Some(x)
+        }
+
+        fn on_start(&mut self) {
+            panic!("no camera")
+        }
+    }
+
+    #[test]
+    fn hook_failures_in_a_copy_have_no_order() {
+        let pipeline = PipelineBuilder::new()
+            .then_sequential(|| |x: u32| Some(x))
+            .then_nested(2, || PipelineBuilder::new().then_sequential(|| FailToStart))
+            .sink(|| |x: u32| x);
+        for x in 0..10 {
+            let _ = pipeline.post(x);
+        }
+        match pipeline.collect() {
+            Err(PipelineError::HookPanicked { stage, hook, message }) => {
+                assert_eq!(stage, 1);
+                assert_eq!(hook, Hook::OnStart);
+                assert_eq!(message, "no camera");
+            }
+            other => panic!("Unexpected result {:?}", other)
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "one output per value")]
+    fn flat_map_cannot_be_nested_in_a_farm() {
+        PipelineBuilder::<u32, u32, Vec<u32>>::new()
+            .then_nested(2, || PipelineBuilder::new().then_flat_map(1, || |x: u32| vec![x, x]));
+    }
+
+    #[test]
+    fn a_single_copy_can_flat_map() {
+        let pipeline = PipelineBuilder::new()
+            .then_nested(1, || PipelineBuilder::new().then_flat_map(1, || |x: u32| vec![x, x]))
+            .sink_ordered(|| |x: u32| x);
+        for x in 0..5 {
+            pipeline.post(x).unwrap();
+        }
+        assert_eq!(pipeline.collect().unwrap(), vec![0, 0, 1, 1, 2, 2, 3, 3, 4, 4]);
+    }
+}
@@ -0,0 +1,210 @@
+use crate::blocks::*;
+use crate::work_storage::*;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use parking_lot::{Mutex, Condvar};
+
+/*
+ * Public API: A stage that splits each value into parts, processes the parts in
+ * parallel and gathers their outputs back into one output. scatter also returns
+ * whatever gather needs from the value besides the part outputs:
+ *
+ *  impl ScatterGather<Frame, Frame> for DetectEyes {
+ *      type Part = Mat;
+ *      type PartOutput = Vec<Rect>;
+ *      type Kept = Frame;
+ *      fn scatter(&mut self, frame: Frame) -> (Frame, Vec<Mat>) { ... }
+ *      fn gather(&mut self, frame: Frame, eyes: Vec<Vec<Rect>>) -> Option<Frame> { ... }
+ *  }
+ */
+pub trait ScatterGather<TInput, TOutput> {
+    type Part: Send;
+    type PartOutput: Send;
+    type Kept;
+    fn scatter(&mut self, input: TInput) -> (Self::Kept, Vec<Self::Part>);
+    //The outputs are in the order of the parts
+    fn gather(&mut self, kept: Self::Kept, outputs: Vec<Self::PartOutput>) -> Option<TOutput>;
+}
+
+//Public API: Processes the parts of a ScatterGather stage. Each thread that
+//processes parts gets its own
+pub trait SubTask<TPart, TPartOutput> {
+    fn process(&mut self, part: TPart) -> TPartOutput;
+}
+
+
+impl <TPart, TPartOutput, F> SubTask<TPart, TPartOutput> for F where F: FnMut(TPart) -> TPartOutput {
+    fn process(&mut self, part: TPart) -> TPartOutput {
+        (*self)(part)
+    }
+}
+
+//Public API: The mode of a scatter/gather stage. Given by scatter_gather!
+pub struct ScatterGatherMode<TPartFactory> {
+    pub mode: BlockMode,
+    //Threads that only process parts, besides the replicas
+    pub workers: usize,
+    pub part_factory: TPartFactory
+}
+
+impl<TPartFactory> ScatterGatherMode<TPartFactory> {
+    pub fn new(mode: BlockMode, workers: usize, part_factory: TPartFactory) -> ScatterGatherMode<TPartFactory> {
+        ScatterGatherMode {
+            mode: mode,
+            workers: workers,
+            part_factory: part_factory
+        }
+    }
+}
+
+//Internals: The output of a part, or the payload of its panic. None until it is done
+type PartResult<TPartOutput> = Option<Result<TPartOutput, Box<dyn Any + Send>>>;
+
+//Internals: Where the outputs of the parts of one value end up
+struct Gathering<TPartOutput> {
+    state: Mutex<(Vec<PartResult<TPartOutput>>, usize)>,
+    done: Condvar
+}
+
+impl<TPartOutput> Gathering<TPartOutput> {
+    fn new(parts: usize) -> Arc<Gathering<TPartOutput>> {
+        Arc::new(Gathering {
+            state: Mutex::new(((0..parts).map(|_| None).collect(), parts)),
+            done: Condvar::new()
+        })
+    }
+
+    fn complete(&self, index: usize, output: Result<TPartOutput, Box<dyn Any + Send>>) {
+        let mut state = self.state.lock();
+        state.0[index] = Some(output);
+        state.1 -= 1;
+        if state.1 == 0 {
+            self.done.notify_all();
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.state.lock().1 == 0
+    }
+
+    //A part that panicked panics the replica, so the stage reports it as usual
+    fn wait_outputs(&self) -> Vec<TPartOutput> {
+        let mut state = self.state.lock();
+        while state.1 > 0 {
+            self.done.wait(&mut state);
+        }
+        state.0.drain(..).map(|output| match output {
+            Some(Ok(output)) => output,
+            Some(Err(payload)) => panic::resume_unwind(payload),
+            None => unreachable!()
+        }).collect()
+    }
+}
+
+struct PartJob<TPart, TPartOutput> {
+    part: TPart,
+    index: usize,
+    gathering: Arc<Gathering<TPartOutput>>
+}
+
+impl<TPart, TPartOutput> PartJob<TPart, TPartOutput> {
+    fn run<TSubTask: SubTask<TPart, TPartOutput>>(self, task: &mut TSubTask) {
+        let part = self.part;
+        let output = panic::catch_unwind(AssertUnwindSafe(|| task.process(part)));
+        self.gathering.complete(self.index, output);
+    }
+}
+
+/*
+ * Internals: The parts waiting to be processed, shared by the replicas and the
+ * workers of a stage. A replica waiting for its parts processes parts too, of any
+ * value, so the stage can't deadlock even without workers. The workers stop
+ * once every replica handler is gone.
+ */
+pub struct PartPool<TPart, TPartOutput> {
+    queue: Arc<BlockingQueue<PartJob<TPart, TPartOutput>>>,
+    handles: AtomicUsize,
+    workers: usize
+}
+
+impl<TPart: Send, TPartOutput: Send> PartPool<TPart, TPartOutput> {
+    pub fn new(workers: usize) -> Arc<PartPool<TPart, TPartOutput>> {
+        Arc::new(PartPool {
+            queue: BlockingQueue::new(),
+            handles: AtomicUsize::new(0),
+            workers: workers
+        })
+    }
+
+    //What a worker thread runs
+    pub fn work<TSubTask: SubTask<TPart, TPartOutput>>(&self, task: &mut TSubTask) {
+        while let TimestampedWorkItem(WorkItem::Value(job), _) = self.queue.wait_and_dequeue() {
+            job.run(task);
+        }
+    }
+
+    fn run<TSubTask: SubTask<TPart, TPartOutput>>(&self, parts: Vec<TPart>, task: &mut TSubTask) -> Vec<TPartOutput> {
+        let gathering = Gathering::new(parts.len());
+        for (index, part) in parts.into_iter().enumerate() {
+            self.queue.enqueue(WorkItem::Value(PartJob {
+                part: part,
+                index: index,
+                gathering: gathering.clone()
+            }));
+        }
+
+        while !gathering.is_done() {
+            match self.queue.try_dequeue() {
+                Some(TimestampedWorkItem(WorkItem::Value(job), _)) => job.run(task),
+                //Whatever is left is being processed by other threads
+                _ => break
+            }
+        }
+        gathering.wait_outputs()
+    }
+}
+
+//Internals: The handler of a scatter/gather replica
+pub struct Scattering<THandler, TSubTask, TPart: Send, TPartOutput: Send> {
+    handler: THandler,
+    task: TSubTask,
+    pool: Arc<PartPool<TPart, TPartOutput>>
+}
+
+impl<THandler, TSubTask, TPart: Send, TPartOutput: Send> Scattering<THandler, TSubTask, TPart, TPartOutput> {
+    pub fn new(handler: THandler, task: TSubTask, pool: Arc<PartPool<TPart, TPartOutput>>)
+        -> Scattering<THandler, TSubTask, TPart, TPartOutput> {
+        pool.handles.fetch_add(1, Ordering::SeqCst);
+        Scattering {
+            handler: handler,
+            task: task,
+            pool: pool
+        }
+    }
+}
+
+impl<THandler, TSubTask, TPart: Send, TPartOutput: Send> Drop for Scattering<THandler, TSubTask, TPart, TPartOutput> {
+    fn drop(&mut self) {
+        if self.pool.handles.fetch_sub(1, Ordering::SeqCst) == 1 {
+            for _ in 0..self.pool.workers {
+                self.pool.queue.enqueue(WorkItem::Stop);
+            }
+        }
+    }
+}
+
+impl<TInput, TOutput, THandler, TSubTask, TPart, TPartOutput> InOut<TInput, TOutput>
+for Scattering<THandler, TSubTask, TPart, TPartOutput>
+where
+    THandler: ScatterGather<TInput, TOutput, Part = TPart, PartOutput = TPartOutput>,
+    TSubTask: SubTask<TPart, TPartOutput>,
+    TPart: Send,
+    TPartOutput: Send {
+    fn process(&mut self, input: TInput) -> Option<TOutput> {
+        let (kept, parts) = self.handler.scatter(input);
+        let outputs = self.pool.run(parts, &mut self.task);
+        self.handler.gather(kept, outputs)
+    }
+}
@@ -0,0 +1,196 @@
+use crate::blocks::*;
+use crate::work_storage::*;
+use crate::spp::PipelineError;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use parking_lot::{Mutex};
+
+//Public API: An Output node; produces the values of the stream until it returns None
+pub trait Out<TOutput> {
+    fn produce(&mut self) -> Option<TOutput>;
+}
+
+
+impl <TOutput, F> Out<TOutput> for F where F: FnMut() -> Option<TOutput> {
+    fn produce(&mut self) -> Option<TOutput> {
+        (*self)()
+    }
+}
+
+//Public API: The mode of a source stage. Given by source!
+pub struct Source;
+
+/*
+ * Internals: The first stage of a pipeline that produces its own input. It runs the
+ * source on its own thread, numbering the values from 0, and sends Stop once the
+ * source returns None, a value panics, the pipeline fails or it is cancelled. The
+ * pipeline takes no posts. Its end() and Drop cancel the source, which stops
+ * before its next value, while collect() and end_and_wait() let it run out.
+ */
+pub struct SourceBlock<TOutput, TCollected> {
+    stage: usize,
+    next_step: Arc<Box<dyn PipelineBlock<TOutput, TCollected>>>,
+    //Only taken by monitor_posts, the lock just makes the block Sync
+    source: Mutex<Option<Box<dyn Out<TOutput> + Send>>>,
+    cancelled: Arc<AtomicBool>,
+    metrics: Arc<StageRecorder>
+}
+
+impl<TOutput: 'static, TCollected: 'static> SourceBlock<TOutput, TCollected>
+where
+    TOutput: Send,
+{
+    pub fn new<TSource>(
+        stage: usize,
+        next_step: Box<dyn PipelineBlock<TOutput, TCollected>>,
+        source: TSource
+    ) -> SourceBlock<TOutput, TCollected>
+    where TSource: Out<TOutput> + Send + 'static {
+        SourceBlock {
+            stage: stage,
+            next_step: Arc::new(next_step),
+            source: Mutex::new(Some(Box::new(source))),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            metrics: StageRecorder::new(stage, 1)
+        }
+    }
+
+    pub fn monitor_posts(&mut self) -> MonitorLoop {
+        let stage = self.stage;
+        let next_step = self.next_step.clone();
+        let metrics = self.metrics.clone();
+        let cancelled = self.cancelled.clone();
+        let mut source = self.source.get_mut().take().expect("Source stages have a single monitor");
+
+        MonitorLoop::new(move || {
+            let mut order = 0;
+            while !next_step.has_failed() && !cancelled.load(Ordering::SeqCst) {
+                let produced = metrics.produce(0, order, || {
+                    let source = &mut source;
+                    let output = match run_stage(stage, order, || Ok(source.produce())) {
+                        Ok(output) => output,
+                        Err(failure) => {
+                            next_step.report_failure(failure);
+                            None
+                        }
+                    };
+                    match output {
+                        Some(output) => {
+                            metrics.trace_processed(0);
+                            next_step.process_timestamped(TimestampedWorkItem(WorkItem::Value(output), order));
+                            true
+                        }
+                        None => false
+                    }
+                });
+                if !produced {
+                    break;
+                }
+                order += 1;
+            }
+            next_step.process_timestamped(TimestampedWorkItem(WorkItem::Stop, order));
+        })
+    }
+}
+
+impl<TOutput: 'static, TCollected: 'static> PipelineBlock<(), TCollected> for SourceBlock<TOutput, TCollected>
+where
+    TOutput: Send,
+{
+    //Pipeline::post refuses values, see takes_posts. The Stop sent
+    //when the pipeline is done posting is left out, the source ends
+    //the stream unless it is cancelled
+    fn process(&self, _input: WorkItem<()>) {
+    }
+
+    fn process_timestamped(&self, input: TimestampedWorkItem<()>) {
+        self.process(input.0);
+    }
+
+    fn takes_posts(&self) -> bool {
+        false
+    }
+
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    fn collect(self: Box<Self>) -> Vec<TCollected> {
+        match Arc::try_unwrap(self.next_step) {
+            Ok(result) => result.collect(),
+            Err(_) => {
+                panic!("Could not unwrap Arc in call to collect");
+            }
+        }
+    }
+
+    fn results(&self) -> Arc<ResultQueue<TCollected>> {
+        self.next_step.results()
+    }
+
+    fn report_failure(&self, failure: PipelineError) {
+        self.next_step.report_failure(failure)
+    }
+
+    fn has_failed(&self) -> bool {
+        self.next_step.has_failed()
+    }
+
+    fn take_failure(&self) -> Option<PipelineError> {
+        self.next_step.take_failure()
+    }
+
+    fn recorders(&self, recorders: &mut Vec<Arc<StageRecorder>>) {
+        recorders.push(self.metrics.clone());
+        self.next_step.recorders(recorders)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    fn counter(limit: Option<u32>) -> impl FnMut() -> Option<u32> {
+        let mut next = 0;
+        move || {
+            next += 1;
+            match limit {
+                Some(limit) if next > limit => None,
+                _ => Some(next - 1)
+            }
+        }
+    }
+
+    #[test]
+    fn collect_waits_for_the_source_to_run_out() {
+        let pipeline = PipelineBuilder::new()
+            .then_source(|| counter(Some(100)))
+            .sink_ordered(|| |x: u32| x);
+        assert!(matches!(pipeline.post(()), Err(ItemPostError::HasSource)));
+        assert_eq!(pipeline.collect().unwrap(), (0..100).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn end_cancels_an_endless_source() {
+        let mut pipeline = PipelineBuilder::new()
+            .then_source(|| counter(None))
+            .sink_ordered(|| |x: u32| x);
+        let results = pipeline.results();
+        for x in 0..10 {
+            assert_eq!(results.recv(), Some(x));
+        }
+        pipeline.end();
+        assert!(pipeline.end_and_wait().is_ok());
+        let rest: Vec<u32> = results.collect();
+        assert_eq!(rest, (10..10 + rest.len() as u32).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn dropping_the_pipeline_cancels_an_endless_source() {
+        let pipeline = PipelineBuilder::new()
+            .then_source(|| counter(None))
+            .sink(|| |x: u32| x);
+        assert!(pipeline.results().recv().is_some());
+        drop(pipeline);
+    }
+}
@@ -0,0 +1,66 @@
+use crate::blocks::StageRecorder;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+/*
+ * Internals: Chrome trace-event JSON for the spans recorded by the stages, for
+ * chrome://tracing or Perfetto. Each replica gets its own track, named after its
+ * stage, with a "process" event per value and a "forward" event for the time spent
+ * handing it to the next stage. The time a value waited in the stage queue shows
+ * up as an async "queued" event, from its enqueue to its dequeue. With batching,
+ * the values of a batch are dequeued together and processed one after the other.
+ * Timestamps are in microseconds since the first recorded event.
+ */
+pub fn chrome_trace(recorders: &[Arc<StageRecorder>]) -> String {
+    let traces: Vec<_> = recorders.iter().map(|recorder| (recorder.stage(), recorder.trace())).collect();
+
+    let epoch = traces.iter().flat_map(|(_, (enqueued, spans))| {
+        enqueued.iter().map(|(_, time)| *time)
+            .chain(spans.iter().flatten().map(|span| span.dequeued))
+    }).min();
+    let epoch = match epoch {
+        Some(epoch) => epoch,
+        None => return String::from("{\"traceEvents\":[]}")
+    };
+    let micros = |time: Instant| (time - epoch).as_nanos() as f64 / 1000.0;
+
+    let mut events: Vec<String> = vec![];
+    let mut track = 0;
+    for (stage, (enqueued, spans)) in &traces {
+        let mut dequeued = HashMap::new();
+
+        for (replica, spans) in spans.iter().enumerate() {
+            events.push(format!(
+                "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":{},\"args\":{{\"name\":\"stage {} replica {}\"}}}}",
+                track, stage, replica));
+
+            for span in spans {
+                dequeued.insert(span.order, (span.dequeued, track));
+                events.push(format!(
+                    "{{\"name\":\"process\",\"cat\":\"stage {}\",\"ph\":\"X\",\"ts\":{:.3},\"dur\":{:.3},\"pid\":1,\"tid\":{},\"args\":{{\"order\":{}}}}}",
+                    stage, micros(span.started), micros(span.processed) - micros(span.started), track, span.order));
+                if span.forwarded > span.processed {
+                    events.push(format!(
+                        "{{\"name\":\"forward\",\"cat\":\"stage {}\",\"ph\":\"X\",\"ts\":{:.3},\"dur\":{:.3},\"pid\":1,\"tid\":{},\"args\":{{\"order\":{}}}}}",
+                        stage, micros(span.processed), micros(span.forwarded) - micros(span.processed), track, span.order));
+                }
+            }
+            track += 1;
+        }
+
+        //Values still in the queue have no end, they are left out
+        for (order, time) in enqueued {
+            if let Some((dequeued, track)) = dequeued.get(order) {
+                events.push(format!(
+                    "{{\"name\":\"queued\",\"cat\":\"stage {} queue\",\"ph\":\"b\",\"id\":{},\"ts\":{:.3},\"pid\":1,\"tid\":{}}}",
+                    stage, order, micros(*time), track));
+                events.push(format!(
+                    "{{\"name\":\"queued\",\"cat\":\"stage {} queue\",\"ph\":\"e\",\"id\":{},\"ts\":{:.3},\"pid\":1,\"tid\":{}}}",
+                    stage, order, micros(*dequeued), track));
+            }
+        }
+    }
+
+    format!("{{\"traceEvents\":[\n{}\n]}}", events.join(",\n"))
+}
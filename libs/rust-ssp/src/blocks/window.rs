@@ -0,0 +1,407 @@
+use crate::blocks::*;
+use crate::work_storage::*;
+use crate::spp::PipelineError;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use parking_lot::{Mutex};
+
+//Public API: Which values go in each window. A new window starts every step and
+//spans size, so windows tumble when step == size and slide when step < size
+#[derive(Clone, Copy, Debug)]
+pub enum Window {
+    //By the number of values that reached the stage
+    Count { size: u64, step: u64 },
+    //By the order in which items were posted, dropped ones included
+    Orders { size: u64, step: u64 },
+    //By the time values reached the stage, counted from the first one
+    Time { size: Duration, step: Duration }
+}
+
+impl Window {
+    pub fn tumbling_count(size: u64) -> Window {
+        Window::Count { size: size, step: size }
+    }
+
+    pub fn sliding_count(size: u64, step: u64) -> Window {
+        Window::Count { size: size, step: step }
+    }
+
+    pub fn tumbling_orders(size: u64) -> Window {
+        Window::Orders { size: size, step: size }
+    }
+
+    pub fn sliding_orders(size: u64, step: u64) -> Window {
+        Window::Orders { size: size, step: step }
+    }
+
+    pub fn tumbling_time(size: Duration) -> Window {
+        Window::Time { size: size, step: size }
+    }
+
+    pub fn sliding_time(size: Duration, step: Duration) -> Window {
+        Window::Time { size: size, step: step }
+    }
+}
+
+//Public API: What a reducer knows about a window besides its values
+#[derive(Clone, Copy, Debug)]
+pub struct WindowSpan {
+    //Windows are numbered from 0. The output of a window has this order
+    pub index: u64,
+    //The bounds of a time window, the last one ends with the stream. For
+    //the other windows, when their first and last values arrived
+    pub start: Instant,
+    pub end: Instant
+}
+
+//Public API: Turns the values of a window into an output, or None to skip the window
+pub trait Reduce<TInput, TOutput> {
+    fn reduce(&mut self, span: WindowSpan, values: &[TInput]) -> Option<TOutput>;
+}
+
+
+impl <TInput, TOutput, F> Reduce<TInput, TOutput> for F
+where F: FnMut(WindowSpan, &[TInput]) -> Option<TOutput> {
+    fn reduce(&mut self, span: WindowSpan, values: &[TInput]) -> Option<TOutput> {
+        (*self)(span, values)
+    }
+}
+
+/*
+ * Internals: The values of the windows that are still open. Each value has a key,
+ * what its window counts: values seen before it, its order, or nanoseconds since
+ * the first value. Window k holds the keys in [k * step, k * step + size) and
+ * closes once the stream gets past them. When the stream ends, the windows that
+ * started before its end close as they are.
+ */
+struct OpenWindows<TInput> {
+    window: Window,
+    size: u64,
+    step: u64,
+    origin: Option<Instant>,
+    seen: u64,
+    //Next window to close
+    next: u64,
+    keys: VecDeque<(u64, Instant)>,
+    values: VecDeque<TInput>
+}
+
+fn nanos_since(origin: Instant, now: Instant) -> u64 {
+    (now - origin).as_nanos() as u64
+}
+
+impl<TInput> OpenWindows<TInput> {
+    fn new(window: Window) -> OpenWindows<TInput> {
+        let (size, step) = match window {
+            Window::Count { size, step } | Window::Orders { size, step } => (size, step),
+            Window::Time { size, step } => (size.as_nanos() as u64, step.as_nanos() as u64)
+        };
+        assert!(size > 0 && step > 0, "Windows need a size and a step above zero");
+        OpenWindows {
+            window: window,
+            size: size,
+            step: step,
+            origin: None,
+            seen: 0,
+            next: 0,
+            keys: VecDeque::new(),
+            values: VecDeque::new()
+        }
+    }
+
+    fn push(&mut self, value: TInput, order: u64, now: Instant) {
+        let key = match self.window {
+            Window::Count { .. } => self.seen,
+            Window::Orders { .. } => order,
+            Window::Time { .. } => nanos_since(*self.origin.get_or_insert(now), now)
+        };
+        self.seen += 1;
+        self.keys.push_back((key, now));
+        self.values.push_back(value);
+    }
+
+    //How far the stream got, in keys. Time windows only start with the first value
+    fn position(&self, next_order: u64, now: Instant) -> Option<u64> {
+        match self.window {
+            Window::Count { .. } => Some(self.seen),
+            Window::Orders { .. } => Some(next_order),
+            Window::Time { .. } => self.origin.map(|origin| nanos_since(origin, now))
+        }
+    }
+
+    //When the next time window closes, even if no value arrives
+    fn deadline(&self) -> Option<Instant> {
+        match (self.window, self.origin) {
+            (Window::Time { .. }, Some(origin)) => Some(origin + Duration::from_nanos(self.next * self.step + self.size)),
+            _ => None
+        }
+    }
+
+    //Gives each window that closes to the closure, returns whether it produced anything
+    fn close<F>(&mut self, position: u64, ended: bool, now: Instant, mut reduce: F) -> bool
+    where F: FnMut(WindowSpan, &[TInput]) -> bool {
+        let mut produced = false;
+        loop {
+            let start = self.next * self.step;
+            let end = start + self.size;
+            if end > position && !(ended && start < position) {
+                return produced;
+            }
+
+            while let Some(&(key, _)) = self.keys.front() {
+                if key >= start {
+                    break;
+                }
+                self.keys.pop_front();
+                self.values.pop_front();
+            }
+            let count = self.keys.iter().take_while(|(key, _)| *key < end).count();
+
+            let span = match (self.window, self.origin) {
+                (Window::Time { .. }, Some(origin)) => WindowSpan {
+                    index: self.next,
+                    start: origin + Duration::from_nanos(start),
+                    end: origin + Duration::from_nanos(end.min(position))
+                },
+                _ if count > 0 => WindowSpan {
+                    index: self.next,
+                    start: self.keys[0].1,
+                    end: self.keys[count - 1].1
+                },
+                _ => WindowSpan { index: self.next, start: now, end: now }
+            };
+
+            self.next += 1;
+            let values = &self.values.make_contiguous()[..count];
+            produced |= reduce(span, values);
+        }
+    }
+}
+
+/*
+ * Internals: A sequential stage that sees values in the order they were posted
+ * and reduces them window by window. Each window gives one output, or a Dropped
+ * marker when the reducer skips it, numbered by the window index. The stream
+ * after this stage counts windows rather than posted items.
+ */
+pub struct WindowBlock<TInput, TOutput, TCollected> {
+    stage: usize,
+    window: Window,
+    ordered_work: Arc<BlockingOrderedSet<TInput>>,
+    next_step: Arc<Box<dyn PipelineBlock<TOutput, TCollected>>>,
+    //Only taken by monitor_posts, the lock just makes the block Sync
+    reducer: Mutex<Option<Box<dyn Reduce<TInput, TOutput> + Send>>>,
+    counter: AtomicU64,
+    metrics: Arc<StageRecorder>
+}
+
+impl<TInput: 'static, TOutput: 'static, TCollected: 'static> WindowBlock<TInput, TOutput, TCollected>
+where
+    TInput: Send,
+    TOutput: Send,
+{
+    pub fn new<TReducer>(
+        stage: usize,
+        next_step: Box<dyn PipelineBlock<TOutput, TCollected>>,
+        window: Window,
+        reducer: TReducer
+    ) -> WindowBlock<TInput, TOutput, TCollected>
+    where TReducer: Reduce<TInput, TOutput> + Send + 'static {
+        WindowBlock {
+            stage: stage,
+            window: window,
+            ordered_work: BlockingOrderedSet::new(),
+            next_step: Arc::new(next_step),
+            reducer: Mutex::new(Some(Box::new(reducer))),
+            counter: AtomicU64::new(0),
+            metrics: StageRecorder::new(stage, 1)
+        }
+    }
+
+    pub fn monitor_posts(&mut self) -> MonitorLoop {
+        let storage = self.ordered_work.clone();
+        let metrics = self.metrics.clone();
+        let next_step = self.next_step.clone();
+        let stage = self.stage;
+        let mut windows = OpenWindows::new(self.window);
+        let mut reducer = self.reducer.get_mut().take().expect("Window stages have a single monitor");
+
+        //Failures are reported with the window index as the order
+        let mut forward = move |span: WindowSpan, values: &[TInput]| {
+            let output = if next_step.has_failed() {
+                None
+            } else {
+                let reducer = &mut reducer;
+                match run_stage(stage, span.index, || Ok(reducer.reduce(span, values))) {
+                    Ok(output) => output,
+                    Err(failure) => {
+                        next_step.report_failure(failure);
+                        None
+                    }
+                }
+            };
+            match output {
+                Some(output) => {
+                    next_step.process_timestamped(TimestampedWorkItem(WorkItem::Value(output), span.index));
+                    true
+                }
+                None => {
+                    next_step.process_timestamped(TimestampedWorkItem(WorkItem::Dropped, span.index));
+                    false
+                }
+            }
+        };
+        let next_step = self.next_step.clone();
+
+        MonitorLoop::new(move || {
+            let mut next_item = 0;
+            loop {
+                let item = metrics.idle(0, || storage.wait_and_remove_until(next_item, windows.deadline()));
+                metrics.sample_depth(|| storage.len());
+                let now = Instant::now();
+                match item {
+                    Some(TimestampedWorkItem(WorkItem::Value(val), order)) => {
+                        debug_assert!(order == next_item);
+                        next_item += 1;
+                        metrics.batch(0, 1);
+                        metrics.busy(0, order, || {
+                            windows.push(val, order, now);
+                            match windows.position(next_item, now) {
+                                Some(position) => windows.close(position, false, now, &mut forward),
+                                None => false
+                            }
+                        });
+                    }
+                    Some(TimestampedWorkItem(WorkItem::Dropped, _)) => {
+                        next_item += 1;
+                        if let Some(position) = windows.position(next_item, now) {
+                            windows.close(position, false, now, &mut forward);
+                        }
+                    }
+                    Some(TimestampedWorkItem(WorkItem::Stop, order)) => {
+                        if let Some(position) = windows.position(order, now) {
+                            windows.close(position, true, now, &mut forward);
+                        }
+                        next_step.process_timestamped(TimestampedWorkItem(WorkItem::Stop, windows.next));
+                        break;
+                    }
+                    //A time window is due
+                    None => {
+                        if let Some(position) = windows.position(next_item, now) {
+                            windows.close(position, false, now, &mut forward);
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
+
+impl<TInput: 'static, TOutput: 'static, TCollected: 'static> PipelineBlock<TInput, TCollected>
+for WindowBlock<TInput, TOutput, TCollected>
+where
+    TInput: Send,
+    TOutput: Send,
+{
+    //Items posted from the outside are stamped with a counter
+    fn process(&self, input: WorkItem<TInput>) {
+        let order = self.counter.fetch_add(1, Ordering::SeqCst);
+        self.process_timestamped(TimestampedWorkItem(input, order));
+    }
+
+    fn process_timestamped(&self, input: TimestampedWorkItem<TInput>) {
+        if let TimestampedWorkItem(WorkItem::Value(_), order) = input {
+            self.metrics.trace_enqueue(order);
+        }
+        self.ordered_work.enqueue(input);
+    }
+
+    fn collect(self: Box<Self>) -> Vec<TCollected> {
+        match Arc::try_unwrap(self.next_step) {
+            Ok(result) => result.collect(),
+            Err(_) => {
+                panic!("Could not unwrap Arc in call to collect");
+            }
+        }
+    }
+
+    fn results(&self) -> Arc<ResultQueue<TCollected>> {
+        self.next_step.results()
+    }
+
+    fn report_failure(&self, failure: PipelineError) {
+        self.next_step.report_failure(failure)
+    }
+
+    fn has_failed(&self) -> bool {
+        self.next_step.has_failed()
+    }
+
+    fn take_failure(&self) -> Option<PipelineError> {
+        self.next_step.take_failure()
+    }
+
+    fn recorders(&self, recorders: &mut Vec<Arc<StageRecorder>>) {
+        recorders.push(self.metrics.clone());
+        self.next_step.recorders(recorders)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::thread;
+    use std::time::Duration;
+
+    fn windows(window: Window, inputs: Vec<u32>) -> Vec<Vec<u32>> {
+        let pipeline = PipelineBuilder::new()
+            .then_window(window, || |_: WindowSpan, values: &[u32]| Some(values.to_vec()))
+            .sink_ordered(|| |values: Vec<u32>| values);
+        for x in inputs {
+            pipeline.post(x).unwrap();
+        }
+        pipeline.collect().unwrap()
+    }
+
+    #[test]
+    fn count_windows_tumble_and_slide() {
+        assert_eq!(windows(Window::tumbling_count(3), (0..10).collect()),
+            vec![vec![0, 1, 2], vec![3, 4, 5], vec![6, 7, 8], vec![9]]);
+        assert_eq!(windows(Window::sliding_count(4, 2), (0..8).collect()),
+            vec![vec![0, 1, 2, 3], vec![2, 3, 4, 5], vec![4, 5, 6, 7], vec![6, 7]]);
+    }
+
+    #[test]
+    fn order_windows_count_dropped_items() {
+        let pipeline = PipelineBuilder::new()
+            .then_parallel(3, || |x: u32| if x.is_multiple_of(2) { Some(x) } else { None })
+            .then_window(Window::tumbling_orders(5), || |_: WindowSpan, values: &[u32]| Some(values.to_vec()))
+            .sink_ordered(|| |values: Vec<u32>| values);
+        for x in 0..20 {
+            pipeline.post(x).unwrap();
+        }
+        assert_eq!(pipeline.collect().unwrap(),
+            vec![vec![0, 2, 4], vec![6, 8], vec![10, 12, 14], vec![16, 18]]);
+    }
+
+    #[test]
+    fn time_windows_close_by_arrival_time() {
+        let pipeline = PipelineBuilder::new()
+            .then_window(Window::tumbling_time(Duration::from_millis(200)), || |span: WindowSpan, values: &[u32]| {
+                if values.is_empty() { None } else { Some((span.index, values.to_vec())) }
+            })
+            .sink_ordered(|| |output: (u64, Vec<u32>)| output);
+        for x in 0..3 {
+            pipeline.post(x).unwrap();
+        }
+        thread::sleep(Duration::from_millis(500));
+        pipeline.post(3).unwrap();
+        let collected = pipeline.collect().unwrap();
+        assert_eq!(collected.len(), 2);
+        assert_eq!(collected[0], (0, vec![0, 1, 2]));
+        assert!(collected[1].0 >= 2);
+        assert_eq!(collected[1].1, vec![3]);
+    }
+}
@@ -0,0 +1,576 @@
+use std::hash::Hash;
+use std::sync::Arc;
+use crate::blocks::*;
+use crate::spp::Pipeline;
+use crate::work_storage::{WorkStorage, StorageBackend, BlockingBackend, WaitStrategy, Batching};
+
+//Builds the blocks added so far, given the block that comes after them and the
+//number of their first stage, which is not 0 when they are nested in a pipeline
+type BuildChain<TInput, TOutput, TCollected> = Box<dyn FnOnce(
+    Box<dyn PipelineBlock<TOutput, TCollected>>,
+    usize,
+    &mut Vec<MonitorLoop>
+) -> Box<dyn PipelineBlock<TInput, TCollected>>>;
+
+//Public API: The mode of a stage, as given by the stage macros. It builds the block
+//that runs the stage: BlockMode an InOutBlock, FlatMap an InOutBlock that outputs
+//sequences followed by a Flatten. The block gets as many queues as the mode asks for,
+//and takes as many stage numbers
+pub trait StageMode<TInput, TOutput, TCollected, THandler, TMarker> {
+    fn queues(&self) -> usize {
+        1
+    }
+
+    fn stages(&self) -> usize {
+        1
+    }
+
+    //Whether the stage gives one output, or Dropped marker, per value,
+    //with the order of the value. Only such stages can be nested in a farm
+    fn one_to_one(&self) -> bool {
+        true
+    }
+
+    //False for a source, which can only be the first stage of a pipeline
+    fn takes_input(&self) -> bool {
+        true
+    }
+
+    fn build_stage<TFactory>(
+        self,
+        stage: usize,
+        next_step: Box<dyn PipelineBlock<TOutput, TCollected>>,
+        factory: TFactory,
+        work_queues: Vec<Arc<dyn WorkStorage<TInput>>>,
+        batching: Batching,
+        monitors: &mut Vec<MonitorLoop>
+    ) -> Box<dyn PipelineBlock<TInput, TCollected>>
+    where
+        TFactory: FnMut() -> THandler + Send + 'static;
+}
+
+impl<TInput, TOutput, TCollected, THandler, TMarker> StageMode<TInput, TOutput, TCollected, THandler, TMarker> for BlockMode
+where
+    TInput: Send + 'static,
+    TOutput: Send + 'static,
+    TCollected: 'static,
+    THandler: IntoInOutHandler<TInput, TOutput, TMarker> {
+
+    fn build_stage<TFactory>(
+        self,
+        stage: usize,
+        next_step: Box<dyn PipelineBlock<TOutput, TCollected>>,
+        factory: TFactory,
+        work_queues: Vec<Arc<dyn WorkStorage<TInput>>>,
+        batching: Batching,
+        monitors: &mut Vec<MonitorLoop>
+    ) -> Box<dyn PipelineBlock<TInput, TCollected>>
+    where
+        TFactory: FnMut() -> THandler + Send + 'static {
+        let mut block = InOutBlock::new(stage, next_step, self, factory, work_queues[0].clone(), batching);
+        monitors.extend(block.monitor_posts());
+        Box::new(block)
+    }
+}
+
+impl<TInput, TOutput, TCollected, THandler, TMarker> StageMode<TInput, TOutput, TCollected, THandler, TMarker> for FlatMap
+where
+    TInput: Send + 'static,
+    TOutput: Send + 'static,
+    TCollected: 'static,
+    THandler: IntoInOutHandler<TInput, Vec<TOutput>, TMarker> {
+
+    fn one_to_one(&self) -> bool {
+        false
+    }
+
+    fn build_stage<TFactory>(
+        self,
+        stage: usize,
+        next_step: Box<dyn PipelineBlock<TOutput, TCollected>>,
+        factory: TFactory,
+        work_queues: Vec<Arc<dyn WorkStorage<TInput>>>,
+        batching: Batching,
+        monitors: &mut Vec<MonitorLoop>
+    ) -> Box<dyn PipelineBlock<TInput, TCollected>>
+    where
+        TFactory: FnMut() -> THandler + Send + 'static {
+        let FlatMap(mode) = self;
+        let flatten: Box<dyn PipelineBlock<Vec<TOutput>, TCollected>> = Box::new(Flatten::new(next_step));
+        let mut block = InOutBlock::new(stage, flatten, mode, factory, work_queues[0].clone(), batching);
+        monitors.extend(block.monitor_posts());
+        Box::new(block)
+    }
+}
+
+impl<TInput, TOutput, TCollected, THandler, TPartFactory, TSubTask> StageMode<TInput, TOutput, TCollected, THandler, ()>
+for ScatterGatherMode<TPartFactory>
+where
+    TInput: Send + 'static,
+    TOutput: Send + 'static,
+    TCollected: 'static,
+    THandler: ScatterGather<TInput, TOutput> + Send + 'static,
+    THandler::Part: 'static,
+    THandler::PartOutput: 'static,
+    TPartFactory: FnMut() -> TSubTask + Send + 'static,
+    TSubTask: SubTask<THandler::Part, THandler::PartOutput> + Send + 'static {
+
+    fn build_stage<TFactory>(
+        self,
+        stage: usize,
+        next_step: Box<dyn PipelineBlock<TOutput, TCollected>>,
+        mut factory: TFactory,
+        work_queues: Vec<Arc<dyn WorkStorage<TInput>>>,
+        batching: Batching,
+        monitors: &mut Vec<MonitorLoop>
+    ) -> Box<dyn PipelineBlock<TInput, TCollected>>
+    where
+        TFactory: FnMut() -> THandler + Send + 'static {
+        let ScatterGatherMode { mode, workers, mut part_factory } = self;
+        let pool = PartPool::new(workers);
+        for _ in 0..workers {
+            let mut task = part_factory();
+            let pool = pool.clone();
+            monitors.push(MonitorLoop::new(move || pool.work(&mut task)));
+        }
+
+        let replica_factory = move || Scattering::new(factory(), part_factory(), pool.clone());
+        let mut block = InOutBlock::new(stage, next_step, mode, replica_factory, work_queues[0].clone(), batching);
+        monitors.extend(block.monitor_posts());
+        Box::new(block)
+    }
+}
+
+impl<TInput, TOutput, TCollected, THandler, TMarker, TKeyFn, TKey> StageMode<TInput, TOutput, TCollected, THandler, TMarker> for Keyed<TKeyFn>
+where
+    TInput: Send + 'static,
+    TOutput: Send + 'static,
+    TCollected: 'static,
+    THandler: IntoInOutHandler<TInput, TOutput, TMarker>,
+    TKeyFn: Fn(&TInput) -> TKey + Send + Sync + 'static,
+    TKey: Hash {
+
+    fn queues(&self) -> usize {
+        self.replicas as usize
+    }
+
+    fn build_stage<TFactory>(
+        self,
+        stage: usize,
+        next_step: Box<dyn PipelineBlock<TOutput, TCollected>>,
+        factory: TFactory,
+        work_queues: Vec<Arc<dyn WorkStorage<TInput>>>,
+        batching: Batching,
+        monitors: &mut Vec<MonitorLoop>
+    ) -> Box<dyn PipelineBlock<TInput, TCollected>>
+    where
+        TFactory: FnMut() -> THandler + Send + 'static {
+        let Keyed { replicas, key } = self;
+        let mut block = InOutBlock::new(
+            stage, next_step, BlockMode::Parallel(replicas), factory, work_queues[0].clone(), batching);
+        block.route_by_key(KeyRouter::new(stage, key, work_queues));
+        monitors.extend(block.monitor_posts());
+        Box::new(block)
+    }
+}
+
+impl<TInput, TOutput, TCollected, THandler> StageMode<TInput, TOutput, TCollected, THandler, ()> for Window
+where
+    TInput: Send + 'static,
+    TOutput: Send + 'static,
+    TCollected: 'static,
+    THandler: Reduce<TInput, TOutput> + Send + 'static {
+
+    //Windows give one output per window
+    fn one_to_one(&self) -> bool {
+        false
+    }
+
+    //Windows are reduced by a single replica that doesn't use the work queue
+    fn build_stage<TFactory>(
+        self,
+        stage: usize,
+        next_step: Box<dyn PipelineBlock<TOutput, TCollected>>,
+        mut factory: TFactory,
+        _work_queues: Vec<Arc<dyn WorkStorage<TInput>>>,
+        _batching: Batching,
+        monitors: &mut Vec<MonitorLoop>
+    ) -> Box<dyn PipelineBlock<TInput, TCollected>>
+    where
+        TFactory: FnMut() -> THandler + Send + 'static {
+        let mut block = WindowBlock::new(stage, next_step, self, factory());
+        monitors.push(block.monitor_posts());
+        Box::new(block)
+    }
+}
+
+impl<TOutput, TCollected, THandler> StageMode<(), TOutput, TCollected, THandler, ()> for Source
+where
+    TOutput: Send + 'static,
+    TCollected: 'static,
+    THandler: Out<TOutput> + Send + 'static {
+
+    fn one_to_one(&self) -> bool {
+        false
+    }
+
+    fn takes_input(&self) -> bool {
+        false
+    }
+
+    //The source runs on a single thread and has no work queue
+    fn build_stage<TFactory>(
+        self,
+        stage: usize,
+        next_step: Box<dyn PipelineBlock<TOutput, TCollected>>,
+        mut factory: TFactory,
+        _work_queues: Vec<Arc<dyn WorkStorage<()>>>,
+        _batching: Batching,
+        monitors: &mut Vec<MonitorLoop>
+    ) -> Box<dyn PipelineBlock<(), TCollected>>
+    where
+        TFactory: FnMut() -> THandler + Send + 'static {
+        let mut block = SourceBlock::new(stage, next_step, factory());
+        monitors.push(block.monitor_posts());
+        Box::new(block)
+    }
+}
+
+impl<TInput, TOutput, TCollected, TBackend> StageMode<TInput, TOutput, TCollected, (), ()>
+for Nested<TInput, TOutput, TCollected, TBackend>
+where
+    TInput: Send + 'static,
+    TOutput: Send + 'static,
+    TCollected: 'static {
+
+    fn stages(&self) -> usize {
+        self.copies[0].stages
+    }
+
+    fn one_to_one(&self) -> bool {
+        self.copies.iter().all(|copy| copy.one_to_one)
+    }
+
+    fn takes_input(&self) -> bool {
+        self.copies.iter().all(|copy| copy.takes_input)
+    }
+
+    //A single copy is spliced in as it is. Copies are built with the stage
+    //numbers of the pipeline they are nested in, and don't use the work queue
+    fn build_stage<TFactory>(
+        self,
+        stage: usize,
+        next_step: Box<dyn PipelineBlock<TOutput, TCollected>>,
+        _factory: TFactory,
+        _work_queues: Vec<Arc<dyn WorkStorage<TInput>>>,
+        _batching: Batching,
+        monitors: &mut Vec<MonitorLoop>
+    ) -> Box<dyn PipelineBlock<TInput, TCollected>>
+    where
+        TFactory: FnMut() + Send + 'static {
+        let mut copies = self.copies;
+        if copies.len() == 1 {
+            let copy = copies.pop().unwrap();
+            return (copy.build)(next_step, stage, monitors);
+        }
+
+        let number_of_copies = copies.len();
+        let mut copies = copies.into_iter();
+        let farm = NestedFarm::new(next_step, number_of_copies, |output| {
+            let copy = copies.next().unwrap();
+            (copy.build)(output, stage, monitors)
+        });
+        Box::new(farm)
+    }
+}
+
+/*
+ * Public API: Builds a pipeline stage by stage. Each stage takes the output type of
+ * the previous one, so a mismatch is reported on the method call that adds the stage.
+ * Factories are called once per replica:
+ *
+ *  let pipeline = PipelineBuilder::new()
+ *      .then_parallel(8, || LoadImage)
+ *      .then_sequential_ordered(|| Tracker::new())
+ *      .sink(|| |image: Image| image.save());
+ *
+ * Blocks need their next step when they are created, so nothing is built until
+ * a sink is added. The sink starts the pipeline and returns it.
+ *
+ * Stage queues come from a StorageBackend, BlockingBackend unless with_backend
+ * is called, and replicas wait on them with WaitStrategy::Block unless
+ * with_wait_strategy is called. Replicas take one item at a time unless
+ * with_batching is called. These apply to the stages added after them.
+ */
+pub struct PipelineBuilder<TInput, TOutput, TCollected, TBackend = BlockingBackend> {
+    stages: usize,
+    //Whether every stage so far is one_to_one
+    pub(crate) one_to_one: bool,
+    //False once the first stage is a source
+    pub(crate) takes_input: bool,
+    backend: TBackend,
+    wait: WaitStrategy,
+    batching: Batching,
+    build: BuildChain<TInput, TOutput, TCollected>
+}
+
+impl<TInput: 'static, TCollected: 'static> PipelineBuilder<TInput, TInput, TCollected>
+where
+    TInput: Send {
+
+    pub fn new() -> PipelineBuilder<TInput, TInput, TCollected> {
+        PipelineBuilder {
+            stages: 0,
+            one_to_one: true,
+            takes_input: true,
+            backend: BlockingBackend,
+            wait: WaitStrategy::Block,
+            batching: Batching::single(),
+            build: Box::new(|next_step, _, _| next_step)
+        }
+    }
+}
+
+impl<TInput: 'static, TCollected: 'static> Default for PipelineBuilder<TInput, TInput, TCollected>
+where
+    TInput: Send {
+
+    fn default() -> PipelineBuilder<TInput, TInput, TCollected> {
+        PipelineBuilder::new()
+    }
+}
+
+impl<TCollected: 'static, TBackend> PipelineBuilder<(), (), TCollected, TBackend>
+where
+    TBackend: StorageBackend {
+
+    //The first stage produces the values of the stream, see source!.
+    //Panics if the builder already has stages
+    pub fn then_source<TNext, TFactory, THandler>(self, factory: TFactory)
+        -> PipelineBuilder<(), TNext, TCollected, TBackend>
+    where
+        TNext: Send + 'static,
+        TFactory: FnMut() -> THandler + Send + 'static,
+        THandler: Out<TNext> + Send + 'static {
+        self.then_stage(Source, factory, None)
+    }
+}
+
+impl<TInput: 'static, TOutput: 'static, TCollected: 'static, TBackend> PipelineBuilder<TInput, TOutput, TCollected, TBackend>
+where
+    TInput: Send,
+    TOutput: Send,
+    TBackend: StorageBackend {
+
+    pub fn with_backend<TNewBackend: StorageBackend>(self, backend: TNewBackend)
+        -> PipelineBuilder<TInput, TOutput, TCollected, TNewBackend> {
+        PipelineBuilder {
+            stages: self.stages,
+            one_to_one: self.one_to_one,
+            takes_input: self.takes_input,
+            backend: backend,
+            wait: self.wait,
+            batching: self.batching,
+            build: self.build
+        }
+    }
+
+    pub fn with_wait_strategy(mut self, wait: WaitStrategy) -> PipelineBuilder<TInput, TOutput, TCollected, TBackend> {
+        self.wait = wait;
+        self
+    }
+
+    pub fn with_batching(mut self, batching: Batching) -> PipelineBuilder<TInput, TOutput, TCollected, TBackend> {
+        self.batching = batching;
+        self
+    }
+
+    pub fn then_parallel<TNext, TFactory, THandler, TMarker>(self, replicas: i32, factory: TFactory)
+        -> PipelineBuilder<TInput, TNext, TCollected, TBackend>
+    where
+        TNext: Send + 'static,
+        TFactory: FnMut() -> THandler + Send + 'static,
+        THandler: IntoInOutHandler<TOutput, TNext, TMarker> {
+        self.then_stage(BlockMode::Parallel(replicas), factory, None)
+    }
+
+    pub fn then_parallel_ordered<TNext, TFactory, THandler, TMarker>(self, replicas: i32, factory: TFactory)
+        -> PipelineBuilder<TInput, TNext, TCollected, TBackend>
+    where
+        TNext: Send + 'static,
+        TFactory: FnMut() -> THandler + Send + 'static,
+        THandler: IntoInOutHandler<TOutput, TNext, TMarker> {
+        self.then_stage(BlockMode::ParallelOrdered(replicas), factory, None)
+    }
+
+    pub fn then_sequential<TNext, TFactory, THandler, TMarker>(self, factory: TFactory)
+        -> PipelineBuilder<TInput, TNext, TCollected, TBackend>
+    where
+        TNext: Send + 'static,
+        TFactory: FnMut() -> THandler + Send + 'static,
+        THandler: IntoInOutHandler<TOutput, TNext, TMarker> {
+        self.then_stage(BlockMode::Sequential(OrderingMode::Unordered), factory, None)
+    }
+
+    pub fn then_sequential_ordered<TNext, TFactory, THandler, TMarker>(self, factory: TFactory)
+        -> PipelineBuilder<TInput, TNext, TCollected, TBackend>
+    where
+        TNext: Send + 'static,
+        TFactory: FnMut() -> THandler + Send + 'static,
+        THandler: IntoInOutHandler<TOutput, TNext, TMarker> {
+        self.then_stage(BlockMode::Sequential(OrderingMode::Ordered), factory, None)
+    }
+
+    //Each value gives any number of outputs, see flat_map!
+    pub fn then_flat_map<TNext, TFactory, THandler>(self, replicas: i32, mut factory: TFactory)
+        -> PipelineBuilder<TInput, TNext, TCollected, TBackend>
+    where
+        TNext: Send + 'static,
+        TFactory: FnMut() -> THandler + Send + 'static,
+        THandler: FlatInOut<TOutput, TNext> + Send + 'static {
+        self.then_stage(FlatMap(BlockMode::Parallel(replicas)), move || FlatMapped(factory()), None)
+    }
+
+    //Each value is split into parts that the replicas and the workers process
+    //in parallel, see scatter_gather!
+    pub fn then_scatter_gather<TNext, TFactory, THandler, TPartFactory, TSubTask>(
+        self,
+        replicas: i32,
+        workers: usize,
+        factory: TFactory,
+        part_factory: TPartFactory
+    ) -> PipelineBuilder<TInput, TNext, TCollected, TBackend>
+    where
+        TNext: Send + 'static,
+        TFactory: FnMut() -> THandler + Send + 'static,
+        THandler: ScatterGather<TOutput, TNext> + Send + 'static,
+        THandler::Part: 'static,
+        THandler::PartOutput: 'static,
+        TPartFactory: FnMut() -> TSubTask + Send + 'static,
+        TSubTask: SubTask<THandler::Part, THandler::PartOutput> + Send + 'static {
+        let mode = ScatterGatherMode::new(BlockMode::Parallel(replicas), workers, part_factory);
+        self.then_stage(mode, factory, None)
+    }
+
+    //Values with the same key always go to the same replica, see keyed!
+    pub fn then_keyed<TNext, TKeyFn, TKey, TFactory, THandler, TMarker>(self, replicas: i32, key: TKeyFn, factory: TFactory)
+        -> PipelineBuilder<TInput, TNext, TCollected, TBackend>
+    where
+        TNext: Send + 'static,
+        TKeyFn: Fn(&TOutput) -> TKey + Send + Sync + 'static,
+        TKey: Hash,
+        TFactory: FnMut() -> THandler + Send + 'static,
+        THandler: IntoInOutHandler<TOutput, TNext, TMarker> {
+        self.then_stage(Keyed::new(replicas, key), factory, None)
+    }
+
+    //Values are reduced window by window, see window!
+    pub fn then_window<TNext, TFactory, THandler>(self, window: Window, factory: TFactory)
+        -> PipelineBuilder<TInput, TNext, TCollected, TBackend>
+    where
+        TNext: Send + 'static,
+        TFactory: FnMut() -> THandler + Send + 'static,
+        THandler: Reduce<TOutput, TNext> + Send + 'static {
+        self.then_stage(window, factory, None)
+    }
+
+    //Each replica is a copy of a whole pipeline, see nested!
+    pub fn then_nested<TNext, TFactory, TSubBackend>(self, replicas: i32, factory: TFactory)
+        -> PipelineBuilder<TInput, TNext, TCollected, TBackend>
+    where
+        TNext: Send + 'static,
+        TFactory: FnMut() -> PipelineBuilder<TOutput, TNext, TCollected, TSubBackend>,
+        TSubBackend: 'static {
+        self.then_stage(Nested::new(replicas, factory), || (), None)
+    }
+
+    //Adds the stages of a pipeline that has no sink, as if they were added here
+    pub fn then_pipeline<TNext, TSubBackend>(self, stages: PipelineBuilder<TOutput, TNext, TCollected, TSubBackend>)
+        -> PipelineBuilder<TInput, TNext, TCollected, TBackend>
+    where
+        TNext: Send + 'static,
+        TSubBackend: 'static {
+        self.then_stage(Nested { copies: vec![stages] }, || (), None)
+    }
+
+    //Any kind of stage, with an optional bound on its queue. Used by pipeline!
+    pub fn then_stage<TNext, TMode, TFactory, THandler, TMarker>(
+        self,
+        mode: TMode,
+        factory: TFactory,
+        capacity: Option<usize>
+    ) -> PipelineBuilder<TInput, TNext, TCollected, TBackend>
+    where
+        TNext: Send + 'static,
+        TMode: StageMode<TOutput, TNext, TCollected, THandler, TMarker> + 'static,
+        TFactory: FnMut() -> THandler + Send + 'static {
+        let stage = self.stages;
+        assert!(stage == 0 || mode.takes_input(), "Only the first stage of a pipeline can be a source");
+        let stages = mode.stages();
+        let one_to_one = self.one_to_one && mode.one_to_one();
+        let takes_input = self.takes_input && mode.takes_input();
+        let work_queues: Vec<_> = (0..mode.queues())
+            .map(|_| self.wait.apply(self.backend.create::<TOutput>(capacity)))
+            .collect();
+        let batching = self.batching;
+        let build_previous = self.build;
+        PipelineBuilder {
+            stages: stage + stages,
+            one_to_one: one_to_one,
+            takes_input: takes_input,
+            backend: self.backend,
+            wait: self.wait,
+            batching: batching,
+            build: Box::new(move |next_step, first_stage, monitors| {
+                let block = mode.build_stage(first_stage + stage, next_step, factory, work_queues, batching, monitors);
+                build_previous(block, first_stage, monitors)
+            })
+        }
+    }
+
+    pub fn sink<TFactory, THandler, TMarker>(self, factory: TFactory) -> Pipeline<TInput, TCollected>
+    where
+        TCollected: Send,
+        TFactory: FnMut() -> THandler + Send + 'static,
+        THandler: IntoInHandler<TOutput, TCollected, TMarker> {
+        self.sink_stage(BlockMode::Sequential(OrderingMode::Unordered), factory, None)
+    }
+
+    pub fn sink_ordered<TFactory, THandler, TMarker>(self, factory: TFactory) -> Pipeline<TInput, TCollected>
+    where
+        TCollected: Send,
+        TFactory: FnMut() -> THandler + Send + 'static,
+        THandler: IntoInHandler<TOutput, TCollected, TMarker> {
+        self.sink_stage(BlockMode::Sequential(OrderingMode::Ordered), factory, None)
+    }
+
+    pub fn sink_parallel<TFactory, THandler, TMarker>(self, replicas: i32, factory: TFactory) -> Pipeline<TInput, TCollected>
+    where
+        TCollected: Send,
+        TFactory: FnMut() -> THandler + Send + 'static,
+        THandler: IntoInHandler<TOutput, TCollected, TMarker> {
+        self.sink_stage(BlockMode::Parallel(replicas), factory, None)
+    }
+
+    //Adds the last stage, builds every block and starts the pipeline
+    pub fn sink_stage<TFactory, THandler, TMarker>(
+        self,
+        mode: BlockMode,
+        factory: TFactory,
+        capacity: Option<usize>
+    ) -> Pipeline<TInput, TCollected>
+    where
+        TCollected: Send,
+        TFactory: FnMut() -> THandler + Send + 'static,
+        THandler: IntoInHandler<TOutput, TCollected, TMarker> {
+        let mut monitors = Vec::<MonitorLoop>::new();
+        let work_queue = self.wait.apply(self.backend.create::<TOutput>(capacity));
+        let mut block = InBlock::new(self.stages, mode, factory, work_queue, self.batching);
+        monitors.extend(block.monitor_posts());
+        let initial_block = (self.build)(Box::new(block), 0, &mut monitors);
+
+        let mut pipeline = Pipeline::new(initial_block, monitors);
+        pipeline.start();
+        pipeline
+    }
+}
@@ -135,7 +135,7 @@ pub fn process_images(threads: i32) {
         pipeline.post(path).unwrap();
     }
 
-    pipeline.end_and_wait();
+    pipeline.end_and_wait().unwrap();
 }
 
 fn load_all_images() -> Vec<ImageToProcess> {
@@ -158,7 +158,7 @@ fn load_all_images() -> Vec<ImageToProcess> {
         pipeline.post(path).unwrap();
     }
 
-    let collected = pipeline.collect();
+    let collected = pipeline.collect().unwrap();
     println!("All {:?} images loaded", collected.len());
     collected
 }
@@ -260,7 +260,7 @@ pub fn process_images_no_IO(threads: i32) -> f64 {
         pipeline.post(entry).unwrap();
     }
 
-    pipeline.end_and_wait();
+    pipeline.end_and_wait().unwrap();
 
     let end = time::precise_time_s();
     return end - start;
@@ -0,0 +1,142 @@
+use std::collections::BTreeMap;
+use std::panic;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::thread::JoinHandle;
+use crate::spp::*;
+use crate::work_storage::{WorkItem, TimestampedWorkItem};
+
+//Public API: Pipes any iterator through a pipeline. The pipeline is built and fed
+//on a dedicated thread, so the outputs can be consumed while items are still posted:
+//
+//  let compressed: Vec<_> = chunks.into_iter()
+//      .through_ordered(|| pipeline![parallel!(Compress, 8), collect!()])
+//      .collect();
+pub trait PipelineIterator: Iterator + Sized + Send + 'static
+where Self::Item: Send + 'static {
+
+    //Outputs come out as soon as the last stage produces them
+    fn through<TCollected, TBuild>(self, build: TBuild) -> PipelineIter<TCollected>
+    where
+        TCollected: Send + 'static,
+        TBuild: FnOnce() -> Pipeline<Self::Item, TCollected> + Send + 'static {
+        PipelineIter::new(self, build, false)
+    }
+
+    //Outputs come out in the order of the items that produced them
+    fn through_ordered<TCollected, TBuild>(self, build: TBuild) -> PipelineIter<TCollected>
+    where
+        TCollected: Send + 'static,
+        TBuild: FnOnce() -> Pipeline<Self::Item, TCollected> + Send + 'static {
+        PipelineIter::new(self, build, true)
+    }
+}
+
+impl<TIterator> PipelineIterator for TIterator
+where TIterator: Iterator + Send + 'static, TIterator::Item: Send + 'static {}
+
+
+//Public API: The outputs of an iterator piped through a pipeline. It stops early
+//if a stage fails, wait() then returns the error. Dropping it stops feeding the
+//pipeline, the items already posted are still processed on the feeding thread
+pub struct PipelineIter<TCollected> {
+    receiver: ResultReceiver<TCollected>,
+    //Next order to emit and the outputs that arrived ahead of it.
+    //None for the unordered mode
+    reorder: Option<(u64, BTreeMap<u64, Option<TCollected>>)>,
+    feeder: Option<JoinHandle<Result<(), PipelineError>>>,
+    //Checked by the feeding thread before each item
+    cancelled: Arc<AtomicBool>
+}
+
+impl<TCollected: Send + 'static> PipelineIter<TCollected> {
+
+    fn new<TIterator, TBuild>(items: TIterator, build: TBuild, ordered: bool) -> PipelineIter<TCollected>
+    where
+        TIterator: Iterator + Send + 'static,
+        TIterator::Item: Send + 'static,
+        TBuild: FnOnce() -> Pipeline<TIterator::Item, TCollected> + Send + 'static {
+
+        let (sender, receiver) = mpsc::channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let feeding = cancelled.clone();
+
+        let feeder = thread::spawn(move || {
+            let mut pipeline = build();
+            sender.send(pipeline.results()).unwrap();
+            for item in items {
+                if feeding.load(Ordering::Relaxed) || pipeline.post(item).is_err() {
+                    break;
+                }
+            }
+            pipeline.end_and_wait()
+        });
+
+        let receiver = match receiver.recv() {
+            Ok(receiver) => receiver,
+            //The build closure panicked
+            Err(_) => panic::resume_unwind(feeder.join().unwrap_err())
+        };
+
+        PipelineIter {
+            receiver: receiver,
+            reorder: if ordered { Some((0, BTreeMap::new())) } else { None },
+            feeder: Some(feeder),
+            cancelled: cancelled
+        }
+    }
+
+    //Waits for the feeding thread and the pipeline to finish. Outputs that were
+    //not consumed yet are discarded
+    pub fn wait(mut self) -> Result<(), PipelineError> {
+        match self.feeder.take() {
+            Some(feeder) => match feeder.join() {
+                Ok(result) => result,
+                Err(payload) => panic::resume_unwind(payload)
+            },
+            None => Ok(())
+        }
+    }
+}
+
+impl<TCollected> Drop for PipelineIter<TCollected> {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+impl<TCollected> Iterator for PipelineIter<TCollected> {
+    type Item = TCollected;
+
+    fn next(&mut self) -> Option<TCollected> {
+        let (next_item, waiting) = match &mut self.reorder {
+            Some((next_item, waiting)) => (next_item, waiting),
+            None => return self.receiver.recv()
+        };
+
+        loop {
+            while let Some(output) = waiting.remove(next_item) {
+                *next_item += 1;
+                if output.is_some() {
+                    return output;
+                }
+            }
+
+            match self.receiver.recv_item() {
+                Some(TimestampedWorkItem(WorkItem::Value(output), order)) => {
+                    waiting.insert(order, Some(output));
+                }
+                Some(TimestampedWorkItem(_, order)) => {
+                    waiting.insert(order, None);
+                }
+                //Gaps are only left when a stage failed. Skip over them
+                None => match waiting.keys().next() {
+                    Some(order) => *next_item = *order,
+                    None => return None
+                }
+            }
+        }
+    }
+}
@@ -2,8 +2,12 @@ pub mod blocks;
 pub mod work_storage;
 #[macro_use]
 pub mod spp;
+pub mod builder;
+pub mod iter;
 
 
 pub use spp::*;
+pub use builder::*;
 pub use blocks::*;
 pub use work_storage::*;
+pub use iter::*;
@@ -1,75 +1,182 @@
 
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
 use std::thread::JoinHandle;
 use crate::blocks::*;
-use crate::work_storage::WorkItem;
+use crate::blocks::trace::chrome_trace;
+use crate::work_storage::{WorkItem, TimestampedWorkItem, ResultQueue};
+use parking_lot::{RwLock};
 
-pub struct Pipeline<TInput, TOutput, TCollected> {
+pub struct Pipeline<TInput, TCollected> {
     signaled_end: bool,
-    initial_block: Option<InOutBlock<TInput, TOutput, TCollected>>,
+    feed: Arc<Feed<TInput, TCollected>>,
     monitors: Vec<MonitorLoop>,
     threads: Vec<JoinHandle<()>>
 }
 
-impl<TInput: 'static, TOutput: 'static, TCollected: 'static> Pipeline<TInput, TOutput, TCollected> 
+//Internals: What a pipeline shares with its producers. The pipeline counts as a
+//producer too, and the last one of them to be done posting ends the stream
+struct Feed<TInput, TCollected> {
+    //Taken by collect, once nothing can post anymore
+    initial_block: RwLock<Option<Box<dyn PipelineBlock<TInput, TCollected>>>>,
+    producers: AtomicUsize
+}
+
+impl<TInput, TCollected> Feed<TInput, TCollected> {
+    fn post(&self, item: TInput) -> Result<(), ItemPostError> {
+        match &*self.initial_block.read() {
+            Some(block) if !block.takes_posts() => Err(ItemPostError::HasSource),
+            Some(block) if block.has_failed() => Err(ItemPostError::PipelineFailed),
+            Some(block) => {
+                block.process(WorkItem::Value(item));
+                Ok(())
+            }
+            None => Err(ItemPostError::UnknownError)
+        }
+    }
+
+    fn join(&self) {
+        self.producers.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn leave(&self) {
+        if self.producers.fetch_sub(1, Ordering::SeqCst) == 1 {
+            if let Some(block) = &*self.initial_block.read() {
+                block.process(WorkItem::Stop);
+            }
+        }
+    }
+
+    fn cancel(&self) {
+        if let Some(block) = &*self.initial_block.read() {
+            block.cancel();
+        }
+    }
+}
+
+impl<TInput: 'static, TCollected: 'static> Pipeline<TInput, TCollected> 
 where
-    TInput: Send,
-    TInput: Sync {
+    TInput: Send {
    
     pub fn new(
-        initial_block: InOutBlock<TInput, TOutput, TCollected>,
+        initial_block: Box<dyn PipelineBlock<TInput, TCollected>>,
         monitors: Vec<MonitorLoop>
-    ) -> Pipeline<TInput, TOutput, TCollected> {
+    ) -> Pipeline<TInput, TCollected> {
         Pipeline {
-            initial_block: Some(initial_block),
+            feed: Arc::new(Feed {
+                initial_block: RwLock::new(Some(initial_block)),
+                producers: AtomicUsize::new(1)
+            }),
             monitors: monitors,
             threads: vec![],
             signaled_end: false
         }
     }
 
-    fn end(&mut self) {
-        self.signaled_end = true;
-        match &self.initial_block {
-            Some(block) => block.send_stop(),
-            None => {}
-        }
+    //Signals the end of the stream without waiting for it, so that a
+    //result receiver on this thread can take the remaining results.
+    //The stream only ends once every producer is dropped too. A source
+    //stage is cancelled and stops before its next value
+    pub fn end(&mut self) {
+        self.feed.cancel();
+        self.signal_end();
     }
 
-    pub fn end_and_wait(&mut self) {
-        self.end();
+    //Returns the error of the first item that failed, if any stage failed.
+    //A source stage runs until it returns None
+    pub fn end_and_wait(&mut self) -> Result<(), PipelineError> {
+        self.signal_end();
         let all_threads = std::mem::replace(&mut self.threads, vec![]);
         for thread in all_threads {
             thread.join().unwrap();
         }
+        match self.feed.initial_block.read().as_ref().and_then(|block| block.take_failure()) {
+            Some(failure) => Err(failure),
+            None => Ok(())
+        }
     }
 
     pub fn post(&self, item: TInput) -> Result<(), ItemPostError> {
         if self.signaled_end {
             return Err(ItemPostError::StreamEnded);
         }
-        match &self.initial_block {
-            Some(block) => {
-                block.process(WorkItem::Value(item));
-                Ok(())
-            }
-            None => Err(ItemPostError::UnknownError)
+        self.feed.post(item)
+    }
+
+    //A handle that posts to this pipeline from other threads
+    pub fn producer(&self) -> Result<Producer<TInput, TCollected>, ItemPostError> {
+        if self.signaled_end {
+            return Err(ItemPostError::StreamEnded);
+        }
+        self.feed.join();
+        Ok(Producer { feed: self.feed.clone() })
+    }
+
+    //Results can be taken while the stream runs. collect() only returns
+    //the ones that no receiver took
+    pub fn results(&self) -> ResultReceiver<TCollected> {
+        match &*self.feed.initial_block.read() {
+            Some(block) => ResultReceiver { queue: block.results() },
+            None => panic!("Pipeline has no blocks")
         }
-        
     }
 
-    pub fn collect(mut self) -> Vec<TCollected> {
-        self.end_and_wait();
+    fn recorders(&self) -> Vec<Arc<StageRecorder>> {
+        let mut recorders = vec![];
+        if let Some(block) = &*self.feed.initial_block.read() {
+            block.recorders(&mut recorders);
+        }
+        recorders
+    }
 
-        let current_block = std::mem::replace(&mut self.initial_block, None);
+    //One snapshot per stage, in pipeline order. Can be taken while the stream runs
+    pub fn metrics(&self) -> Vec<StageMetrics> {
+        self.recorders().iter().map(|recorder| recorder.snapshot()).collect()
+    }
+
+    //Starts recording when each value is enqueued, dequeued, processed and
+    //forwarded by each stage. Only values posted after this call are traced
+    pub fn enable_tracing(&self) {
+        for recorder in self.recorders() {
+            recorder.enable_tracing();
+        }
+    }
+
+    //The recorded trace as Chrome trace-event JSON, for chrome://tracing or Perfetto
+    pub fn chrome_trace(&self) -> String {
+        chrome_trace(&self.recorders())
+    }
+
+    pub fn write_chrome_trace<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.chrome_trace())
+    }
+
+    pub fn collect(mut self) -> Result<Vec<TCollected>, PipelineError> {
+        self.end_and_wait()?;
+
+        let current_block = self.feed.initial_block.write().take();
         match current_block {
             Some(block) => {
-                Box::new(block).collect()     
+                Ok(block.collect())
             }
-            None => vec![]
+            None => Ok(vec![])
         }
     }
 
+    fn signal_end(&mut self) {
+        if self.signaled_end {
+            return;
+        }
+        self.signaled_end = true;
+        self.feed.leave();
+    }
+
     pub fn start(&mut self) {
         let monitors = std::mem::replace(&mut self.monitors, vec![]);
         
@@ -81,13 +188,13 @@ where
     }
 }
 
-impl<TInput, TOutput, TCollected> Drop for Pipeline<TInput, TOutput, TCollected> {
+impl<TInput, TCollected> Drop for Pipeline<TInput, TCollected> {
     fn drop(&mut self) {
-
-        let block = std::mem::replace(&mut self.initial_block, None);
+        self.feed.cancel();
 
         if !self.signaled_end {
-            block.unwrap().send_stop();
+            self.signaled_end = true;
+            self.feed.leave();
         }
 
         let all_threads = std::mem::replace(&mut self.threads, vec![]);
@@ -98,50 +205,263 @@ impl<TInput, TOutput, TCollected> Drop for Pipeline<TInput, TOutput, TCollected>
 }
 
 
+//Public API: Posts to a pipeline from any thread. Items get a single sequence of
+//orders, in the order they were posted, so the items of each producer keep
+//their relative order. Clones post to the same pipeline, and the stream ends
+//once the pipeline and every producer are done posting
+pub struct Producer<TInput, TCollected> {
+    feed: Arc<Feed<TInput, TCollected>>
+}
+
+impl<TInput, TCollected> Producer<TInput, TCollected> {
+    pub fn post(&self, item: TInput) -> Result<(), ItemPostError> {
+        self.feed.post(item)
+    }
+}
+
+impl<TInput, TCollected> Clone for Producer<TInput, TCollected> {
+    fn clone(&self) -> Producer<TInput, TCollected> {
+        self.feed.join();
+        Producer { feed: self.feed.clone() }
+    }
+}
+
+impl<TInput, TCollected> Drop for Producer<TInput, TCollected> {
+    fn drop(&mut self) {
+        self.feed.leave();
+    }
+}
+
+
+//Public API: Takes the results of the last stage as they are produced.
+//Iterating blocks until the next result arrives, and stops after the last one.
+//If a stage fails the results stop early, end_and_wait returns the error
+pub struct ResultReceiver<TCollected> {
+    queue: Arc<ResultQueue<TCollected>>
+}
+
+impl<TCollected> ResultReceiver<TCollected> {
+    pub fn recv(&self) -> Option<TCollected> {
+        self.recv_with_order().map(|(_, item)| item)
+    }
+
+    //Also gives the order in which the item was posted to the pipeline
+    pub fn recv_with_order(&self) -> Option<(u64, TCollected)> {
+        loop {
+            match self.recv_item()? {
+                TimestampedWorkItem(WorkItem::Value(item), order) => return Some((order, item)),
+                _ => continue
+            }
+        }
+    }
+
+    //Also gives the Dropped markers of items that produced no result
+    pub(crate) fn recv_item(&self) -> Option<TimestampedWorkItem<TCollected>> {
+        self.queue.wait_and_pop()
+    }
+
+    pub fn with_order(self) -> WithOrder<TCollected> {
+        WithOrder { receiver: self }
+    }
+}
+
+impl<TCollected> Iterator for ResultReceiver<TCollected> {
+    type Item = TCollected;
+
+    fn next(&mut self) -> Option<TCollected> {
+        self.recv()
+    }
+}
+
+pub struct WithOrder<TCollected> {
+    receiver: ResultReceiver<TCollected>
+}
+
+impl<TCollected> Iterator for WithOrder<TCollected> {
+    type Item = (u64, TCollected);
+
+    fn next(&mut self) -> Option<(u64, TCollected)> {
+        self.receiver.recv_with_order()
+    }
+}
+
+
 #[derive(Debug)]
 pub enum ItemPostError {
     StreamEnded,
+    //A stage failed or panicked, end_and_wait or collect return the error
+    PipelineFailed,
+    //The first stage is a source, which produces the values of the stream
+    HasSource,
     UnknownError
 }
 
+//Stages are numbered from 0, in the order they were given to pipeline!
+#[derive(Debug)]
+pub enum PipelineError {
+    //A fallible stage returned an error for the item with this order
+    StageFailed { stage: usize, order: u64, error: StageError },
+    //A stage panicked while processing the item with this order
+    StagePanicked { stage: usize, order: u64, message: String },
+    //The on_start or on_end hook of a stage panicked, no item was being processed
+    HookPanicked { stage: usize, hook: Hook, message: String }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hook {
+    OnStart,
+    OnEnd
+}
+
+impl PipelineError {
+    pub fn stage(&self) -> usize {
+        match self {
+            PipelineError::StageFailed { stage, .. } => *stage,
+            PipelineError::StagePanicked { stage, .. } => *stage,
+            PipelineError::HookPanicked { stage, .. } => *stage
+        }
+    }
+
+    //The order of the item that failed, None for hooks
+    pub fn order(&self) -> Option<u64> {
+        match self {
+            PipelineError::StageFailed { order, .. } => Some(*order),
+            PipelineError::StagePanicked { order, .. } => Some(*order),
+            PipelineError::HookPanicked { .. } => None
+        }
+    }
+
+    //Where the failure happened in the stream: on_start before
+    //every item, on_end after all of them
+    pub(crate) fn position(&self) -> u64 {
+        match self {
+            PipelineError::HookPanicked { hook: Hook::OnStart, .. } => 0,
+            PipelineError::HookPanicked { hook: Hook::OnEnd, .. } => u64::MAX,
+            failure => failure.order().unwrap_or(0)
+        }
+    }
+
+    //Stages nested in a farm number items on their own, see NestedFarm
+    pub(crate) fn with_order(mut self, new_order: u64) -> PipelineError {
+        match &mut self {
+            PipelineError::StageFailed { order, .. } => *order = new_order,
+            PipelineError::StagePanicked { order, .. } => *order = new_order,
+            PipelineError::HookPanicked { .. } => {}
+        }
+        self
+    }
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PipelineError::StageFailed { stage, order, error } =>
+                write!(f, "stage {} failed on item {}: {}", stage, order, error),
+            PipelineError::StagePanicked { stage, order, message } =>
+                write!(f, "stage {} panicked on item {}: {}", stage, order, message),
+            PipelineError::HookPanicked { stage, hook: Hook::OnStart, message } =>
+                write!(f, "stage {} panicked in on_start: {}", stage, message),
+            PipelineError::HookPanicked { stage, hook: Hook::OnEnd, message } =>
+                write!(f, "stage {} panicked in on_end: {}", stage, message)
+        }
+    }
+}
+
+//The source of a failed stage is the error it returned
+impl Error for PipelineError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PipelineError::StageFailed { error, .. } => Some(error),
+            PipelineError::StagePanicked { .. } | PipelineError::HookPanicked { .. } => None
+        }
+    }
+}
+
+//The stage macros expand to (mode, factory, capacity) tuples,
+//which pipeline! hands to a PipelineBuilder one by one
 #[macro_export]
 macro_rules! pipeline_propagate {
-    ($threads:expr, $s1:expr) => {
+    ($builder:expr, $s1:expr) => {
         {
-            let (mode, factory) = $s1;
-            let mut block = InBlock::new(mode, factory);
-            $threads.push(block.monitor_posts());
-            block
+            let (mode, factory, capacity) = $s1;
+            $builder.sink_stage(mode, factory, capacity)
         }
     };
 
-    ($threads:expr, $s1:expr $(, $tail:expr)*) => {
+    ($builder:expr, $s1:expr $(, $tail:expr)*) => {
         {
-            let (mode, factory) = $s1;
-            let mut block = InOutBlock::new(
-                Box::new(pipeline_propagate!($threads, $($tail),*)),
-                mode, factory);
-            $threads.extend(block.monitor_posts());
-            block
+            let (mode, factory, capacity) = $s1;
+            let builder = $builder.then_stage(mode, factory, capacity);
+            pipeline_propagate!(builder, $($tail),*)
         }
     };
 }
 
 
+#[macro_export]
+macro_rules! pipeline_option {
+    ($builder:expr, backend, $backend:expr) => { $builder.with_backend($backend) };
+    ($builder:expr, wait, $wait:expr) => { $builder.with_wait_strategy($wait) };
+    ($builder:expr, batch, $batching:expr) => { $builder.with_batching($batching) };
+}
+
+
 #[macro_export]
 macro_rules! pipeline {
+    //Options for every stage go before the stages:
+    //pipeline!(backend: ChannelBackend, wait: WaitStrategy::BusySpin; stages...)
+    ($($option:ident: $value:expr),+; $s1:expr $(, $tail:expr)*) => {
+        {
+            let builder = PipelineBuilder::new();
+            $(let builder = pipeline_option!(builder, $option, $value);)+
+            pipeline_propagate!(builder, $s1 $(, $tail)*)
+        }
+    };
     ($s1:expr $(, $tail:expr)*) => {
         {
-            let mut monitors = Vec::<MonitorLoop>::new();
-            let (mode, factory) = $s1;
-            let mut block = InOutBlock::new(
-                Box::new(pipeline_propagate!(monitors, $($tail),*)),
-                mode, factory);
-            monitors.extend(block.monitor_posts());
+            pipeline_propagate!(PipelineBuilder::new(), $s1 $(, $tail)*)
+        }
+    };
+}
+
+
+//Like pipeline!, without the last stage. Gives a PipelineBuilder
+//that can be nested in a pipeline or extended with then_pipeline
+#[macro_export]
+macro_rules! sub_pipeline {
+    ($($option:ident: $value:expr),+; $($stage:expr),*) => {
+        {
+            let builder = PipelineBuilder::new();
+            $(let builder = pipeline_option!(builder, $option, $value);)+
+            $(let builder = {
+                let (mode, factory, capacity) = $stage;
+                builder.then_stage(mode, factory, capacity)
+            };)*
+            builder
+        }
+    };
+    ($($stage:expr),*) => {
+        {
+            let builder = PipelineBuilder::new();
+            $(let builder = {
+                let (mode, factory, capacity) = $stage;
+                builder.then_stage(mode, factory, capacity)
+            };)*
+            builder
+        }
+    };
+}
 
-            let mut pipeline = Pipeline::new(block, monitors);
-            pipeline.start();
-            pipeline
+
+//The first stage of a pipeline that produces its own values, until the
+//expression, an Out or a closure, returns None. The pipeline takes no posts
+#[macro_export]
+macro_rules! source {
+    ($block:expr) => {
+        {
+            let mode = Source;
+            let factory = move || $block;
+            (mode, factory, None)
         }
     };
 }
@@ -152,8 +472,120 @@ macro_rules! parallel {
     ($block:expr, $threads:expr) => {
         {
             let mode = BlockMode::Parallel($threads);
-            let factory: Box<FnMut() -> Box<InOut<_,_>>> = Box::new(move || Box::new($block));
-            (mode, factory)
+            let factory = move || $block;
+            (mode, factory, None)
+        }
+    };
+    //The third argument bounds the stage queue, so producers block when it is full
+    ($block:expr, $threads:expr, $capacity:expr) => {
+        {
+            let mode = BlockMode::Parallel($threads);
+            let factory = move || $block;
+            (mode, factory, Some($capacity))
+        }
+    };
+}
+
+
+#[macro_export]
+macro_rules! parallel_ordered {
+    ($block:expr, $threads:expr) => {
+        {
+            let mode = BlockMode::ParallelOrdered($threads);
+            let factory = move || $block;
+            (mode, factory, None)
+        }
+    };
+    ($block:expr, $threads:expr, $capacity:expr) => {
+        {
+            let mode = BlockMode::ParallelOrdered($threads);
+            let factory = move || $block;
+            (mode, factory, Some($capacity))
+        }
+    };
+}
+
+
+//Each value gives a sequence of outputs, returned as a Vec or any other
+//IntoIterator. Outputs keep the order of their values, and within a value
+//the order of the sequence
+#[macro_export]
+macro_rules! flat_map {
+    ($block:expr, $threads:expr) => {
+        {
+            let mode = FlatMap(BlockMode::Parallel($threads));
+            let factory = move || FlatMapped($block);
+            (mode, factory, None)
+        }
+    };
+    ($block:expr, $threads:expr, $capacity:expr) => {
+        {
+            let mode = FlatMap(BlockMode::Parallel($threads));
+            let factory = move || FlatMapped($block);
+            (mode, factory, Some($capacity))
+        }
+    };
+}
+
+
+//Values with the same key, as given by the second expression, are processed
+//by the same replica in the order they were posted. Replicas can keep per-key state
+#[macro_export]
+macro_rules! keyed {
+    ($block:expr, $key:expr, $threads:expr) => {
+        {
+            let mode = Keyed::new($threads, $key);
+            let factory = move || $block;
+            (mode, factory, None)
+        }
+    };
+    ($block:expr, $key:expr, $threads:expr, $capacity:expr) => {
+        {
+            let mode = Keyed::new($threads, $key);
+            let factory = move || $block;
+            (mode, factory, Some($capacity))
+        }
+    };
+}
+
+
+//The first expression is the ScatterGather, the second processes the parts.
+//Besides the replicas, the workers only process parts
+#[macro_export]
+macro_rules! scatter_gather {
+    ($block:expr, $part:expr, $threads:expr, $workers:expr) => {
+        {
+            let mode = ScatterGatherMode::new(BlockMode::Parallel($threads), $workers, move || $part);
+            let factory = move || $block;
+            (mode, factory, None)
+        }
+    };
+}
+
+
+//The first expression gives a pipeline without a sink, see sub_pipeline!. Each
+//replica runs its own copy, built by evaluating the expression again
+#[macro_export]
+macro_rules! nested {
+    ($pipeline:expr, $threads:expr) => {
+        {
+            let mode = Nested::new($threads, move || $pipeline);
+            let factory = || ();
+            (mode, factory, None)
+        }
+    };
+}
+
+
+//The first expression reduces the values of each window to one output,
+//the second is the Window. Outputs come in window order
+#[macro_export]
+macro_rules! window {
+    ($block:expr, $window:expr) => {
+        {
+            let mode: Window = $window;
+            let factory = move || $block;
+            (mode, factory, None)
         }
     };
 }
@@ -164,8 +596,15 @@ macro_rules! sequential {
     ($block:expr) => {
         {
             let mode = BlockMode::Sequential(OrderingMode::Unordered);
-            let factory: Box<FnMut() -> Box<In<_, _>>> = Box::new(move || Box::new($block));
-            (mode, factory)
+            let factory = move || $block;
+            (mode, factory, None)
+        }
+    };
+    ($block:expr, $capacity:expr) => {
+        {
+            let mode = BlockMode::Sequential(OrderingMode::Unordered);
+            let factory = move || $block;
+            (mode, factory, Some($capacity))
         }
     };
 }
@@ -175,8 +614,8 @@ macro_rules! sequential_ordered {
     ($block:expr) => {
         {
             let mode = BlockMode::Sequential(OrderingMode::Ordered);
-            let factory: Box<FnMut() -> Box<In<_, _>>> = Box::new(move || Box::new($block));
-            (mode, factory)
+            let factory = move || $block;
+            (mode, factory, None)
         }
     };
 }
@@ -189,6 +628,11 @@ macro_rules! collect {
             sequential!(move |item: _| {item})
         }
     };
+    ($capacity:expr) => {
+        {
+            sequential!(move |item: _| {item}, $capacity)
+        }
+    };
 }
 
 
@@ -199,4 +643,54 @@ macro_rules! collect_ordered {
             sequential_ordered!(move |item: _| {item})
         }
     };
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn producers_keep_the_order_of_their_items() {
+        let pipeline = PipelineBuilder::new()
+            .then_parallel_ordered(2, || |item: (u32, u32)| Some(item))
+            .sink_ordered(|| |item: (u32, u32)| item);
+        let producers: Vec<_> = (0..4).map(|_| pipeline.producer().unwrap()).collect();
+        let threads: Vec<_> = producers.into_iter().enumerate().map(|(id, producer)| {
+            thread::spawn(move || {
+                for x in 0..100 {
+                    producer.post((id as u32, x)).unwrap();
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let collected = pipeline.collect().unwrap();
+        assert_eq!(collected.len(), 400);
+        for id in 0..4 {
+            let items: Vec<u32> = collected.iter().filter(|item| item.0 == id).map(|item| item.1).collect();
+            assert_eq!(items, (0..100).collect::<Vec<u32>>());
+        }
+    }
+
+    #[test]
+    fn the_stream_ends_with_the_last_producer() {
+        let mut pipeline = PipelineBuilder::new().sink(|| |x: u32| x);
+        let producer = pipeline.producer().unwrap();
+        let late = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            producer.post(1).unwrap();
+        });
+        pipeline.post(0).unwrap();
+        pipeline.end();
+        assert!(matches!(pipeline.post(2), Err(ItemPostError::StreamEnded)));
+        assert!(matches!(pipeline.producer(), Err(ItemPostError::StreamEnded)));
+
+        let mut collected = pipeline.collect().unwrap();
+        late.join().unwrap();
+        collected.sort();
+        assert_eq!(collected, vec![0, 1]);
+    }
+}
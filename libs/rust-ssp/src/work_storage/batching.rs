@@ -0,0 +1,36 @@
+use std::time::Duration;
+
+/*
+ * Public API: How many items the replicas of a stage take from their queue at once,
+ * and how they hand their outputs to the next stage. A replica waits for one item,
+ * then takes whatever else is ready, up to max_items, waiting at most budget for
+ * more to arrive. That wait follows the WaitStrategy of the stage. The outputs
+ * of a batch are forwarded with a single queue operation. Items keep their
+ * timestamps, so ordered stages still see the original order.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Batching {
+    pub max_items: usize,
+    pub budget: Duration
+}
+
+impl Batching {
+    pub fn new(max_items: usize, budget: Duration) -> Batching {
+        assert!(max_items > 0, "Batches must hold at least one item");
+        Batching {
+            max_items: max_items,
+            budget: budget
+        }
+    }
+
+    //One item per queue operation, the default
+    pub fn single() -> Batching {
+        Batching::new(1, Duration::from_secs(0))
+    }
+}
+
+impl Default for Batching {
+    fn default() -> Batching {
+        Batching::single()
+    }
+}
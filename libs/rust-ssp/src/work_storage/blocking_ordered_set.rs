@@ -1,6 +1,7 @@
 use crate::work_storage::*;
 use std::collections::BTreeMap;
 use std::sync::{Arc};
+use std::time::Instant;
 use parking_lot::{Mutex, Condvar};
 
 pub struct BlockingOrderedSet<T> {
@@ -36,7 +37,30 @@ impl<T> BlockingOrderedSet<T> {
             None => { panic!("Condition variable waited until item was found, but removal failed") }
         }
     }
+
+    //Gives up at the deadline, if there is one
+    pub fn wait_and_remove_until(&self, item: u64, deadline: Option<Instant>) -> Option<TimestampedWorkItem<T>> {
+        let mut storage = self.storage.lock();
+        while !(*storage).contains_key(&item) {
+            match deadline {
+                Some(deadline) => {
+                    if self.new_item_notifier.wait_until(&mut storage, deadline).timed_out() {
+                        break;
+                    }
+                }
+                None => self.new_item_notifier.wait(&mut storage)
+            }
+        }
+        storage.remove(&item)
+    }
+
+    //Includes the items waiting for an earlier one
+    pub fn len(&self) -> usize {
+        self.storage.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.storage.lock().is_empty()
+    }
 }
 
-unsafe impl<T> Send for BlockingOrderedSet<T> {}
-unsafe impl<T> Sync for BlockingOrderedSet<T> {}
@@ -1,51 +1,87 @@
 
 use std::collections::VecDeque;
 use std::sync::{Arc};
-use parking_lot::{Mutex, Condvar};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use parking_lot::{Mutex, MutexGuard, Condvar};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
 use crate::work_storage::*;
 
 
 /*
  * Thread-safe queue for storing work items. Each enqueued item gets a timestamp
- * tag.
+ * tag. When created with a capacity, enqueueing blocks while the queue is full,
+ * which gives backpressure to the producer of the stage.
  */
 pub struct BlockingQueue<T> {
     queue: (Mutex<VecDeque<TimestampedWorkItem<T>>>, Condvar),
-    number_of_inserts: AtomicUsize
+    not_full: Condvar,
+    capacity: Option<usize>,
+    number_of_inserts: AtomicU64
 }
 
 impl<T> BlockingQueue<T> {
 
     pub fn new() -> Arc<BlockingQueue<T>> {
+        BlockingQueue::with_capacity(None)
+    }
+
+    pub fn with_capacity(capacity: Option<usize>) -> Arc<BlockingQueue<T>> {
+        if let Some(capacity) = capacity {
+            assert!(capacity > 0, "Queue capacity must be greater than zero");
+        }
         Arc::new(BlockingQueue {
             queue: (Mutex::new(VecDeque::<TimestampedWorkItem<T>>::new()), 
                     Condvar::new()),
-            number_of_inserts: AtomicUsize::new(0)
+            not_full: Condvar::new(),
+            capacity: capacity,
+            number_of_inserts: AtomicU64::new(0)
         })
     }
 
-    pub fn enqueue(&self, item: WorkItem<T>) -> u64 {
+    //Blocks the caller until there is room for one more item
+    fn wait_for_room(&self, queue: &mut MutexGuard<VecDeque<TimestampedWorkItem<T>>>) {
+        if let Some(capacity) = self.capacity {
+            while queue.len() >= capacity {
+                self.not_full.wait(queue);
+            }
+        }
+    }
+}
+
+impl<T: Send> WorkStorage<T> for BlockingQueue<T> {
+
+    fn enqueue(&self, item: WorkItem<T>) -> u64 {
         let (mutex, cvar) = &self.queue;
         let mut queue = mutex.lock();
-        let current = self.number_of_inserts.load(Ordering::SeqCst);
+        self.wait_for_room(&mut queue);
+        let current = self.number_of_inserts.fetch_add(1, Ordering::SeqCst);
        
         queue.push_back(
-            TimestampedWorkItem(item, current as u64));
-        
-        self.number_of_inserts.store(current + 1, Ordering::SeqCst);
+            TimestampedWorkItem(item, current));
 
         cvar.notify_one();
-        return current as u64;
+        return current;
     }
 
-    pub fn enqueue_timestamped(&self, item: TimestampedWorkItem<T>) {
+    fn enqueue_timestamped(&self, item: TimestampedWorkItem<T>) {
         let (mutex, cvar) = &self.queue;
-        mutex.lock().push_back(item);
+        let mut queue = mutex.lock();
+        self.wait_for_room(&mut queue);
+        queue.push_back(item);
         cvar.notify_one();
     }
+
+    fn enqueue_batch(&self, items: Vec<TimestampedWorkItem<T>>) {
+        let (mutex, cvar) = &self.queue;
+        let mut queue = mutex.lock();
+        for item in items {
+            self.wait_for_room(&mut queue);
+            queue.push_back(item);
+            cvar.notify_one();
+        }
+    }
     
-    pub fn wait_and_dequeue(&self) -> TimestampedWorkItem<T> {
+    fn wait_and_dequeue(&self) -> TimestampedWorkItem<T> {
         let &(ref mutex, ref cvar) = &self.queue;
         let mut queue = mutex.lock();
         while queue.is_empty() {
@@ -57,10 +93,97 @@ impl<T> BlockingQueue<T> {
         let popped = queue.pop_front();
 
         debug_assert!(popped.is_some());
+
+        if self.capacity.is_some() {
+            self.not_full.notify_one();
+        }
        
         popped.unwrap()
     }
+
+    fn wait_and_dequeue_until(&self, deadline: Instant) -> Option<TimestampedWorkItem<T>> {
+        let (mutex, cvar) = &self.queue;
+        let mut queue = mutex.lock();
+        while queue.is_empty() {
+            if cvar.wait_until(&mut queue, deadline).timed_out() {
+                break;
+            }
+        }
+        let popped = queue.pop_front();
+        if popped.is_some() && self.capacity.is_some() {
+            self.not_full.notify_one();
+        }
+        popped
+    }
+
+    fn try_dequeue(&self) -> Option<TimestampedWorkItem<T>> {
+        let (mutex, _) = &self.queue;
+        let popped = mutex.lock().pop_front();
+        if popped.is_some() && self.capacity.is_some() {
+            self.not_full.notify_one();
+        }
+        popped
+    }
+
+    fn len(&self) -> usize {
+        let (mutex, _) = &self.queue;
+        mutex.lock().len()
+    }
 }
 
-unsafe impl<T> Send for BlockingQueue<T> {}
-unsafe impl<T> Sync for BlockingQueue<T> {}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use crate::work_storage::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn producers_wait_for_room_in_a_bounded_queue() {
+        let queue = BlockingQueue::with_capacity(Some(2));
+        queue.enqueue(WorkItem::Value(0u32));
+        queue.enqueue(WorkItem::Value(1u32));
+
+        let enqueued = Arc::new(AtomicBool::new(false));
+        let producer = {
+            let queue = queue.clone();
+            let enqueued = enqueued.clone();
+            thread::spawn(move || {
+                queue.enqueue(WorkItem::Value(2u32));
+                enqueued.store(true, Ordering::SeqCst);
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!enqueued.load(Ordering::SeqCst));
+        assert_eq!(queue.len(), 2);
+
+        queue.wait_and_dequeue();
+        producer.join().unwrap();
+        assert!(enqueued.load(Ordering::SeqCst));
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[test]
+    fn a_bounded_queue_keeps_the_order_of_its_items() {
+        let queue = BlockingQueue::with_capacity(Some(4));
+        let producer = {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                for x in 0..100u32 {
+                    queue.enqueue(WorkItem::Value(x));
+                }
+            })
+        };
+        for x in 0..100u32 {
+            match queue.wait_and_dequeue() {
+                TimestampedWorkItem(WorkItem::Value(value), order) => {
+                    assert_eq!(value, x);
+                    assert_eq!(order, x as u64);
+                }
+                _ => panic!("Expected a value")
+            }
+        }
+        producer.join().unwrap();
+    }
+}
@@ -0,0 +1,73 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use crossbeam_channel::{Sender, Receiver};
+use crate::work_storage::*;
+
+/*
+ * Work queue on top of a crossbeam channel. The queue keeps both ends, so the
+ * channel is never disconnected and send/recv can't fail. Bounded channels give
+ * the same backpressure as a bounded BlockingQueue.
+ */
+pub struct ChannelQueue<T> {
+    sender: Sender<TimestampedWorkItem<T>>,
+    receiver: Receiver<TimestampedWorkItem<T>>,
+    number_of_inserts: AtomicU64
+}
+
+impl<T> ChannelQueue<T> {
+
+    pub fn with_capacity(capacity: Option<usize>) -> Arc<ChannelQueue<T>> {
+        let (sender, receiver) = match capacity {
+            Some(capacity) => {
+                assert!(capacity > 0, "Queue capacity must be greater than zero");
+                crossbeam_channel::bounded(capacity)
+            }
+            None => crossbeam_channel::unbounded()
+        };
+        Arc::new(ChannelQueue {
+            sender: sender,
+            receiver: receiver,
+            number_of_inserts: AtomicU64::new(0)
+        })
+    }
+}
+
+impl<T: Send> WorkStorage<T> for ChannelQueue<T> {
+
+    fn enqueue(&self, item: WorkItem<T>) -> u64 {
+        let current = self.number_of_inserts.fetch_add(1, Ordering::SeqCst);
+        self.enqueue_timestamped(TimestampedWorkItem(item, current));
+        current
+    }
+
+    fn enqueue_timestamped(&self, item: TimestampedWorkItem<T>) {
+        self.sender.send(item).expect("work queue disconnected");
+    }
+
+    fn wait_and_dequeue(&self) -> TimestampedWorkItem<T> {
+        self.receiver.recv().expect("work queue disconnected")
+    }
+
+    fn wait_and_dequeue_until(&self, deadline: Instant) -> Option<TimestampedWorkItem<T>> {
+        self.receiver.recv_deadline(deadline).ok()
+    }
+
+    fn try_dequeue(&self) -> Option<TimestampedWorkItem<T>> {
+        self.receiver.try_recv().ok()
+    }
+
+    fn len(&self) -> usize {
+        self.receiver.len()
+    }
+}
+
+//Public API: Backend that gives every stage a ChannelQueue
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ChannelBackend;
+
+impl StorageBackend for ChannelBackend {
+    fn create<T: Send + 'static>(&self, capacity: Option<usize>) -> Arc<dyn WorkStorage<T>> {
+        ChannelQueue::with_capacity(capacity)
+    }
+}
@@ -0,0 +1,197 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering, fence};
+use std::hint;
+use std::time::Instant;
+use crossbeam_queue::{ArrayQueue, SegQueue};
+use parking_lot::{Mutex, Condvar};
+use crate::work_storage::*;
+
+//Failed pops (or pushes on a full queue) before a thread goes to sleep
+const SPIN_LIMIT: usize = 100;
+
+enum Slots<T> {
+    Unbounded(SegQueue<T>),
+    Bounded(ArrayQueue<T>)
+}
+
+/*
+ * Lock-free work queue for farms with fine-grained items. Items go through a
+ * crossbeam SegQueue, or an ArrayQueue when bounded, and timestamps come from a
+ * fetch_add, so producers and replicas never wait on each other for a lock.
+ *
+ * A thread that finds the queue empty (or full) spins for a while, then sleeps on
+ * a condvar. The mutex is only taken to sleep, or to wake a sleeper up: a thread
+ * registers itself as sleeping and then checks the queue again, while the other
+ * side pushes (or pops) and then checks for sleepers. With SeqCst fences on both
+ * sides, at least one of them sees the other, so no wakeup is lost.
+ */
+pub struct LockFreeQueue<T> {
+    slots: Slots<TimestampedWorkItem<T>>,
+    number_of_inserts: AtomicU64,
+    sleep_lock: Mutex<()>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    sleeping_consumers: AtomicUsize,
+    sleeping_producers: AtomicUsize
+}
+
+impl<T> LockFreeQueue<T> {
+
+    pub fn with_capacity(capacity: Option<usize>) -> Arc<LockFreeQueue<T>> {
+        let slots = match capacity {
+            Some(capacity) => {
+                assert!(capacity > 0, "Queue capacity must be greater than zero");
+                Slots::Bounded(ArrayQueue::new(capacity))
+            }
+            None => Slots::Unbounded(SegQueue::new())
+        };
+        Arc::new(LockFreeQueue {
+            slots: slots,
+            number_of_inserts: AtomicU64::new(0),
+            sleep_lock: Mutex::new(()),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            sleeping_consumers: AtomicUsize::new(0),
+            sleeping_producers: AtomicUsize::new(0)
+        })
+    }
+
+    fn try_push(&self, item: TimestampedWorkItem<T>) -> Result<(), TimestampedWorkItem<T>> {
+        match &self.slots {
+            Slots::Unbounded(queue) => {
+                queue.push(item);
+                Ok(())
+            }
+            Slots::Bounded(queue) => queue.push(item)
+        }
+    }
+
+    fn try_pop(&self) -> Option<TimestampedWorkItem<T>> {
+        match &self.slots {
+            Slots::Unbounded(queue) => queue.pop(),
+            Slots::Bounded(queue) => queue.pop()
+        }
+    }
+
+    //Spins, then sleeps until a producer pushes an item or the deadline passes
+    fn dequeue(&self, deadline: Option<Instant>) -> Option<TimestampedWorkItem<T>> {
+        let mut spins = 0;
+        let item = loop {
+            if let Some(item) = self.try_pop() {
+                break item;
+            }
+            if spins < SPIN_LIMIT {
+                spins += 1;
+                hint::spin_loop();
+                continue;
+            }
+            //Empty: sleep until a producer pushes an item
+            let mut guard = self.sleep_lock.lock();
+            self.sleeping_consumers.fetch_add(1, Ordering::SeqCst);
+            fence(Ordering::SeqCst);
+            if let Some(item) = self.try_pop() {
+                self.sleeping_consumers.fetch_sub(1, Ordering::SeqCst);
+                break item;
+            }
+            let timed_out = match deadline {
+                Some(deadline) => self.not_empty.wait_until(&mut guard, deadline).timed_out(),
+                None => {
+                    self.not_empty.wait(&mut guard);
+                    false
+                }
+            };
+            self.sleeping_consumers.fetch_sub(1, Ordering::SeqCst);
+            if timed_out {
+                drop(guard);
+                match self.try_pop() {
+                    Some(item) => break item,
+                    None => return None
+                }
+            }
+        };
+        if let Slots::Bounded(_) = self.slots {
+            self.wake(&self.sleeping_producers, &self.not_full);
+        }
+        Some(item)
+    }
+
+    fn wake(&self, sleeping: &AtomicUsize, condvar: &Condvar) {
+        fence(Ordering::SeqCst);
+        if sleeping.load(Ordering::SeqCst) > 0 {
+            let _guard = self.sleep_lock.lock();
+            condvar.notify_one();
+        }
+    }
+}
+
+impl<T: Send> WorkStorage<T> for LockFreeQueue<T> {
+
+    fn enqueue(&self, item: WorkItem<T>) -> u64 {
+        let current = self.number_of_inserts.fetch_add(1, Ordering::SeqCst);
+        self.enqueue_timestamped(TimestampedWorkItem(item, current));
+        current
+    }
+
+    fn enqueue_timestamped(&self, item: TimestampedWorkItem<T>) {
+        let mut item = item;
+        let mut spins = 0;
+        loop {
+            item = match self.try_push(item) {
+                Ok(()) => break,
+                Err(item) => item
+            };
+            if spins < SPIN_LIMIT {
+                spins += 1;
+                hint::spin_loop();
+                continue;
+            }
+            //Full: sleep until a replica takes an item
+            let mut guard = self.sleep_lock.lock();
+            self.sleeping_producers.fetch_add(1, Ordering::SeqCst);
+            fence(Ordering::SeqCst);
+            item = match self.try_push(item) {
+                Ok(()) => {
+                    self.sleeping_producers.fetch_sub(1, Ordering::SeqCst);
+                    break;
+                }
+                Err(item) => item
+            };
+            self.not_full.wait(&mut guard);
+            self.sleeping_producers.fetch_sub(1, Ordering::SeqCst);
+        }
+        self.wake(&self.sleeping_consumers, &self.not_empty);
+    }
+
+    fn wait_and_dequeue(&self) -> TimestampedWorkItem<T> {
+        self.dequeue(None).expect("Waits without a deadline always give an item")
+    }
+
+    fn wait_and_dequeue_until(&self, deadline: Instant) -> Option<TimestampedWorkItem<T>> {
+        self.dequeue(Some(deadline))
+    }
+
+    fn try_dequeue(&self) -> Option<TimestampedWorkItem<T>> {
+        let item = self.try_pop();
+        if let (Some(_), Slots::Bounded(_)) = (&item, &self.slots) {
+            self.wake(&self.sleeping_producers, &self.not_full);
+        }
+        item
+    }
+
+    fn len(&self) -> usize {
+        match &self.slots {
+            Slots::Unbounded(queue) => queue.len(),
+            Slots::Bounded(queue) => queue.len()
+        }
+    }
+}
+
+//Public API: Backend that gives every stage a LockFreeQueue
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LockFreeBackend;
+
+impl StorageBackend for LockFreeBackend {
+    fn create<T: Send + 'static>(&self, capacity: Option<usize>) -> Arc<dyn WorkStorage<T>> {
+        LockFreeQueue::with_capacity(capacity)
+    }
+}
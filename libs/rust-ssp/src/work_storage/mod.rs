@@ -1,8 +1,22 @@
 
+pub mod storage;
+pub mod batching;
 pub mod blocking_queue;
+pub mod channel_queue;
+pub mod lock_free_queue;
+pub mod wait_strategy;
 pub mod blocking_ordered_set;
+pub mod reorder_buffer;
+pub mod result_queue;
 pub mod work_item;
 
+pub use storage::{WorkStorage, StorageBackend, BlockingBackend};
+pub use batching::Batching;
 pub use blocking_queue::BlockingQueue;
+pub use channel_queue::{ChannelQueue, ChannelBackend};
+pub use lock_free_queue::{LockFreeQueue, LockFreeBackend};
+pub use wait_strategy::WaitStrategy;
 pub use blocking_ordered_set::BlockingOrderedSet;
+pub use reorder_buffer::ReorderBuffer;
+pub use result_queue::ResultQueue;
 pub use work_item::{WorkItem, TimestampedWorkItem};
\ No newline at end of file
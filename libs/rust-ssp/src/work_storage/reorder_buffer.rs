@@ -0,0 +1,104 @@
+use crate::work_storage::*;
+use std::collections::BTreeMap;
+use std::sync::{Arc};
+use parking_lot::{Mutex};
+
+/*
+ * Reorder window for the output of ordered farms. Replicas push their results
+ * as soon as they finish. Whoever pushes the next expected item emits it, followed
+ * by every consecutive item already waiting. Emission happens under the lock,
+ * so items leave the window in order without a dedicated reordering thread.
+ */
+pub struct ReorderBuffer<T> {
+    storage: Mutex<(u64, BTreeMap<u64, TimestampedWorkItem<T>>)>,
+}
+
+impl<T> ReorderBuffer<T> {
+    pub fn new() -> Arc<ReorderBuffer<T>> {
+        Arc::new(ReorderBuffer {
+            storage: Mutex::new((0, BTreeMap::<u64, TimestampedWorkItem<T>>::new())),
+        })
+    }
+
+    pub fn push<F>(&self, item: TimestampedWorkItem<T>, mut emit: F)
+        where F: FnMut(TimestampedWorkItem<T>) {
+        let mut storage = self.storage.lock();
+        let (ref mut next_item, ref mut waiting) = *storage;
+
+        let order = match item {
+            TimestampedWorkItem(_, order) => order
+        };
+
+        if order != *next_item {
+            waiting.insert(order, item);
+            return;
+        }
+
+        emit(item);
+        *next_item += 1;
+
+        while let Some(item) = waiting.remove(next_item) {
+            emit(item);
+            *next_item += 1;
+        }
+    }
+
+    //Pushes a whole batch and emits what it releases as a single batch,
+    //still under the lock
+    pub fn push_batch<F>(&self, items: Vec<TimestampedWorkItem<T>>, emit: F)
+        where F: FnOnce(Vec<TimestampedWorkItem<T>>) {
+        let mut storage = self.storage.lock();
+        let (ref mut next_item, ref mut waiting) = *storage;
+
+        for item in items {
+            let order = item.1;
+            waiting.insert(order, item);
+        }
+
+        let mut released = vec![];
+        while let Some(item) = waiting.remove(next_item) {
+            released.push(item);
+            *next_item += 1;
+        }
+        if !released.is_empty() {
+            emit(released);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::work_storage::*;
+
+    fn value(x: u64) -> TimestampedWorkItem<u64> {
+        TimestampedWorkItem(WorkItem::Value(x), x)
+    }
+
+    fn orders(items: &[TimestampedWorkItem<u64>]) -> Vec<u64> {
+        items.iter().map(|item| item.1).collect()
+    }
+
+    #[test]
+    fn items_wait_until_the_ones_before_them_arrive() {
+        let buffer = ReorderBuffer::new();
+        let mut emitted = vec![];
+        for x in [2, 0, 3, 1, 5, 4] {
+            buffer.push(value(x), |item| emitted.push(item));
+            if x == 2 {
+                assert!(emitted.is_empty());
+            }
+        }
+        assert_eq!(orders(&emitted), vec![0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn a_batch_releases_what_it_completes() {
+        let buffer = ReorderBuffer::new();
+        let mut emitted = vec![];
+        buffer.push_batch(vec![value(1), value(2)], |items| emitted.push(items));
+        assert!(emitted.is_empty());
+        buffer.push_batch(vec![value(4), value(0)], |items| emitted.push(items));
+        assert_eq!(emitted.len(), 1);
+        assert_eq!(orders(&emitted[0]), vec![0, 1, 2]);
+    }
+}
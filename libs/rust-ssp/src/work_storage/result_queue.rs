@@ -0,0 +1,67 @@
+use std::collections::VecDeque;
+use std::sync::{Arc};
+use parking_lot::{Mutex, Condvar};
+use crate::work_storage::*;
+
+/*
+ * Results of the last stage of a pipeline, tagged with the order of the item
+ * that produced them. collect() drains it once the stream is over, while a
+ * result receiver pops from it while the stream is still running.
+ * Items that produced no result are kept as Dropped markers, so a receiver
+ * that puts results back in order knows not to wait for them.
+ */
+pub struct ResultQueue<T> {
+    state: (Mutex<Results<T>>, Condvar)
+}
+
+//The results not taken yet, and whether the last one was pushed
+type Results<T> = (VecDeque<TimestampedWorkItem<T>>, bool);
+
+impl<T> ResultQueue<T> {
+
+    pub fn new() -> Arc<ResultQueue<T>> {
+        Arc::new(ResultQueue {
+            state: (Mutex::new((VecDeque::new(), false)), Condvar::new())
+        })
+    }
+
+    pub fn push(&self, item: TimestampedWorkItem<T>) {
+        let (mutex, cvar) = &self.state;
+        let mut state = mutex.lock();
+        state.0.push_back(item);
+        cvar.notify_one();
+    }
+
+    //Called by the last stage once it has processed every item
+    pub fn end(&self) {
+        let (mutex, cvar) = &self.state;
+        let mut state = mutex.lock();
+        state.1 = true;
+        cvar.notify_all();
+    }
+
+    //Blocks until there is a result. None means the stream ended and
+    //every result was already taken
+    pub fn wait_and_pop(&self) -> Option<TimestampedWorkItem<T>> {
+        let (mutex, cvar) = &self.state;
+        let mut state = mutex.lock();
+        loop {
+            if let Some(item) = state.0.pop_front() {
+                return Some(item);
+            }
+            if state.1 {
+                return None;
+            }
+            cvar.wait(&mut state);
+        }
+    }
+
+    pub fn drain(&self) -> Vec<T> {
+        let (mutex, _) = &self.state;
+        let mut state = mutex.lock();
+        state.0.drain(..).filter_map(|item| match item {
+            TimestampedWorkItem(WorkItem::Value(item), _) => Some(item),
+            _ => None
+        }).collect()
+    }
+}
@@ -0,0 +1,74 @@
+use std::sync::Arc;
+use std::time::Instant;
+use crate::work_storage::*;
+
+/*
+ * Public API: The queue in front of an unordered stage. Producers put items in,
+ * the replicas of the stage take them out in FIFO order. Ordered stages keep
+ * their items in a BlockingOrderedSet instead, so they don't use this.
+ *
+ * enqueue stamps the item with the number of items enqueued before it. Only the
+ * first stage of a pipeline calls it, so that number becomes the order of the item
+ * in the stream. Later stages keep the timestamp of the upstream item.
+ */
+pub trait WorkStorage<T>: Send + Sync {
+    fn enqueue(&self, item: WorkItem<T>) -> u64;
+    fn enqueue_timestamped(&self, item: TimestampedWorkItem<T>);
+    //Blocks until there is an item
+    fn wait_and_dequeue(&self) -> TimestampedWorkItem<T>;
+    //Blocks until there is an item or the deadline passes, the same way wait_and_dequeue blocks
+    fn wait_and_dequeue_until(&self, deadline: Instant) -> Option<TimestampedWorkItem<T>>;
+    //Returns at once. Used by the wait strategies other than Block
+    fn try_dequeue(&self) -> Option<TimestampedWorkItem<T>>;
+
+    //Backends that can push several items under one lock should override this
+    fn enqueue_batch(&self, items: Vec<TimestampedWorkItem<T>>) {
+        for item in items {
+            self.enqueue_timestamped(item);
+        }
+    }
+
+    //Waits for one item, then waits for more as described in Batching.
+    //A Stop always ends the batch, it is never followed by other items
+    fn wait_and_dequeue_batch(&self, batching: Batching) -> Vec<TimestampedWorkItem<T>> {
+        let mut batch = Vec::with_capacity(batching.max_items);
+        let mut item = self.wait_and_dequeue();
+        let deadline = Instant::now() + batching.budget;
+        loop {
+            let is_stop = matches!(item.0, WorkItem::Stop);
+            batch.push(item);
+            if is_stop || batch.len() >= batching.max_items {
+                return batch;
+            }
+            item = match self.wait_and_dequeue_until(deadline) {
+                Some(item) => item,
+                None => return batch
+            };
+        }
+    }
+    //Only used for the queue depth metrics, it may be approximate
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/*
+ * Public API: Creates the queue of each stage. Given to the builder with
+ * PipelineBuilder::with_backend, or to pipeline! as pipeline!(backend: ..., stages).
+ * A capacity of Some(n) must make producers block while n items are waiting.
+ */
+pub trait StorageBackend {
+    fn create<T: Send + 'static>(&self, capacity: Option<usize>) -> Arc<dyn WorkStorage<T>>;
+}
+
+//Public API: The default backend, a VecDeque behind a mutex
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BlockingBackend;
+
+impl StorageBackend for BlockingBackend {
+    fn create<T: Send + 'static>(&self, capacity: Option<usize>) -> Arc<dyn WorkStorage<T>> {
+        BlockingQueue::with_capacity(capacity)
+    }
+}
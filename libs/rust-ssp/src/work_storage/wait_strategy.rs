@@ -0,0 +1,172 @@
+use std::hint;
+use std::sync::Arc;
+use std::thread::{self, Thread, ThreadId};
+use std::time::{Duration, Instant};
+use crate::work_storage::*;
+use parking_lot::{Mutex};
+
+//Failed polls before SpinThenYield starts yielding
+const SPIN_LIMIT: usize = 100;
+
+//Public API: How the replicas of a stage wait for items when their queue is empty
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum WaitStrategy {
+    //Sleep in the queue's own wait_and_dequeue, a condvar for the built-in
+    //queues. Cheapest on the CPU, but waking a replica takes a while
+    #[default]
+    Block,
+    //Poll the queue without ever giving the core away. Lowest latency,
+    //but each waiting replica keeps a core busy
+    BusySpin,
+    //Poll for a while, then yield to the OS scheduler between polls
+    SpinThenYield,
+    //Sleep between polls until an item is enqueued, or for up to the given time
+    ParkTimeout(Duration),
+}
+
+impl WaitStrategy {
+    //Wraps the queue of a stage, unless it already waits the right way
+    pub fn apply<T: Send + 'static>(self, queue: Arc<dyn WorkStorage<T>>) -> Arc<dyn WorkStorage<T>> {
+        match self {
+            WaitStrategy::Block => queue,
+            strategy => Arc::new(WaitingQueue {
+                queue: queue,
+                strategy: strategy,
+                parked: Mutex::new(vec![])
+            })
+        }
+    }
+}
+
+//Internals: A work queue whose consumers wait with a WaitStrategy
+//instead of the queue's blocking wait
+pub struct WaitingQueue<T> {
+    queue: Arc<dyn WorkStorage<T>>,
+    strategy: WaitStrategy,
+    //Replicas sleeping with ParkTimeout. Each enqueue unparks one of them
+    parked: Mutex<Vec<Thread>>
+}
+
+impl<T> WaitingQueue<T> {
+    fn unpark(&self, items: usize) {
+        if let WaitStrategy::ParkTimeout(_) = self.strategy {
+            let mut parked = self.parked.lock();
+            for _ in 0..items {
+                match parked.pop() {
+                    Some(thread) => thread.unpark(),
+                    None => break
+                }
+            }
+        }
+    }
+
+    //Returns whether the thread was still parked, false if an enqueue unparked it
+    fn unregister(&self, thread: ThreadId) -> bool {
+        let mut parked = self.parked.lock();
+        match parked.iter().position(|parked| parked.id() == thread) {
+            Some(index) => {
+                parked.swap_remove(index);
+                true
+            }
+            None => false
+        }
+    }
+
+    //Polls with the strategy until there is an item or the deadline passes
+    fn wait(&self, deadline: Option<Instant>) -> Option<TimestampedWorkItem<T>> {
+        let mut polls = 0;
+        loop {
+            if let Some(item) = self.queue.try_dequeue() {
+                return Some(item);
+            }
+            let now = Instant::now();
+            if let Some(deadline) = deadline {
+                if now >= deadline {
+                    return None;
+                }
+            }
+            match self.strategy {
+                WaitStrategy::Block => return match deadline {
+                    Some(deadline) => self.queue.wait_and_dequeue_until(deadline),
+                    None => Some(self.queue.wait_and_dequeue())
+                },
+                WaitStrategy::BusySpin => hint::spin_loop(),
+                WaitStrategy::SpinThenYield => {
+                    if polls < SPIN_LIMIT {
+                        polls += 1;
+                        hint::spin_loop();
+                    } else {
+                        thread::yield_now();
+                    }
+                }
+                WaitStrategy::ParkTimeout(timeout) => {
+                    let timeout = match deadline {
+                        Some(deadline) => timeout.min(deadline - now),
+                        None => timeout
+                    };
+                    if let Some(item) = self.park(timeout) {
+                        return Some(item);
+                    }
+                }
+            }
+        }
+    }
+
+    //The thread is registered before the last poll, so an item enqueued
+    //after that poll unparks it. park_timeout returns at once if the
+    //unpark came first
+    fn park(&self, timeout: Duration) -> Option<TimestampedWorkItem<T>> {
+        let current = thread::current();
+        self.parked.lock().push(current.clone());
+        match self.queue.try_dequeue() {
+            Some(item) => {
+                //The unpark meant for this thread goes to another one
+                if !self.unregister(current.id()) {
+                    self.unpark(1);
+                }
+                Some(item)
+            }
+            None => {
+                thread::park_timeout(timeout);
+                self.unregister(current.id());
+                None
+            }
+        }
+    }
+}
+
+impl<T> WorkStorage<T> for WaitingQueue<T> {
+
+    fn enqueue(&self, item: WorkItem<T>) -> u64 {
+        let order = self.queue.enqueue(item);
+        self.unpark(1);
+        order
+    }
+
+    fn enqueue_timestamped(&self, item: TimestampedWorkItem<T>) {
+        self.queue.enqueue_timestamped(item);
+        self.unpark(1);
+    }
+
+    fn enqueue_batch(&self, items: Vec<TimestampedWorkItem<T>>) {
+        let count = items.len();
+        self.queue.enqueue_batch(items);
+        self.unpark(count);
+    }
+
+    fn wait_and_dequeue(&self) -> TimestampedWorkItem<T> {
+        self.wait(None).expect("Waits without a deadline always give an item")
+    }
+
+    fn wait_and_dequeue_until(&self, deadline: Instant) -> Option<TimestampedWorkItem<T>> {
+        self.wait(Some(deadline))
+    }
+
+    fn try_dequeue(&self) -> Option<TimestampedWorkItem<T>> {
+        self.queue.try_dequeue()
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+}
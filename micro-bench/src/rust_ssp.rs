@@ -88,7 +88,7 @@ pub fn rust_ssp_pipeline(size: usize, threads: usize, iter_size1: i32, iter_size
                 k_buffer: vec![0; size],
             }).unwrap();
     }
-    let collection = pipeline.collect();
+    let collection = pipeline.collect().unwrap();
 
     let system_duration = start.elapsed().expect("Failed to get render time?");
     let in_sec = system_duration.as_secs() as f64 + system_duration.subsec_nanos() as f64 * 1e-9;